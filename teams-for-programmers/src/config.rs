@@ -0,0 +1,239 @@
+use serde::Deserialize;
+
+const DEFAULT_ADDRESS: &str = "127.0.0.1:7474";
+const DEFAULT_HISTORY_PATH: &str = "teams_history.jsonl";
+const DEFAULT_ACCOUNTS_PATH: &str = "teams_accounts.json";
+const DEFAULT_MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
+const DEFAULT_HEARTBEAT_INTERVAL: u64 = 10;
+const DEFAULT_HEARTBEAT_TIMEOUT: u64 = 30;
+/// Used if the system has no downloads directory
+const FALLBACK_DOWNLOADS_PATH: &str = "downloads";
+/// Used if the system has no config directory
+const FALLBACK_KEYS_PATH: &str = "keys";
+
+#[derive(clap::Parser, Debug)]
+#[command(about = "Teams, but for programmers")]
+pub struct Cli {
+    /// Config file to use instead of ~/.config/teams/config.toml
+    #[arg(long, global = true)]
+    config: Option<std::path::PathBuf>,
+
+    #[command(subcommand)]
+    command: Mode,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Mode {
+    /// Run the server that routes the messages
+    Server {
+        /// Address to listen on, e.g. 0.0.0.0:7474
+        #[arg(long)]
+        bind: Option<String>,
+        /// File the message history is stored in
+        #[arg(long)]
+        history: Option<std::path::PathBuf>,
+        /// File the accounts with their password hashes are stored in
+        #[arg(long)]
+        accounts: Option<std::path::PathBuf>,
+        /// Largest file in bytes that users may send each other
+        #[arg(long)]
+        max_file_size: Option<u64>,
+        /// Certificate chain in PEM format, together with the key the connections use tls
+        #[arg(long)]
+        tls_cert: Option<std::path::PathBuf>,
+        /// Private key of the certificate in PEM format
+        #[arg(long)]
+        tls_key: Option<std::path::PathBuf>,
+        /// Seconds without any message from the client before it is pinged
+        #[arg(long)]
+        heartbeat_interval: Option<u64>,
+        /// Seconds without any message from the client before the connection counts as dead
+        #[arg(long)]
+        heartbeat_timeout: Option<u64>,
+    },
+    /// Run the terminal client
+    Client {
+        /// Address of the server, e.g. teams.example.com:7474
+        #[arg(long)]
+        connect: Option<String>,
+        /// Username to log in with, asked for if not given
+        #[arg(long)]
+        user: Option<String>,
+        /// Create the account instead of logging in to an existing one
+        #[arg(long)]
+        register: bool,
+        /// Directory received files are saved to
+        #[arg(long)]
+        downloads: Option<std::path::PathBuf>,
+        /// CA certificate in PEM format the server certificate has to be issued by, connects with tls if given
+        #[arg(long)]
+        ca: Option<std::path::PathBuf>,
+        /// Name the server certificate is checked against, defaults to the host of the address
+        #[arg(long)]
+        server_name: Option<String>,
+        /// Directory with the key files for encrypted direct messages, one per user
+        #[arg(long)]
+        keys: Option<std::path::PathBuf>,
+        /// Seconds without any message from the server before it is pinged
+        #[arg(long)]
+        heartbeat_interval: Option<u64>,
+        /// Seconds without any message from the server before the connection counts as dead
+        #[arg(long)]
+        heartbeat_timeout: Option<u64>,
+    },
+}
+
+/// Layout of the config file, every value is optional
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    server: ServerSection,
+    client: ClientSection,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ServerSection {
+    bind: Option<String>,
+    history: Option<std::path::PathBuf>,
+    accounts: Option<std::path::PathBuf>,
+    max_file_size: Option<u64>,
+    tls_cert: Option<std::path::PathBuf>,
+    tls_key: Option<std::path::PathBuf>,
+    heartbeat_interval: Option<u64>,
+    heartbeat_timeout: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ClientSection {
+    connect: Option<String>,
+    user: Option<String>,
+    downloads: Option<std::path::PathBuf>,
+    ca: Option<std::path::PathBuf>,
+    server_name: Option<String>,
+    keys: Option<std::path::PathBuf>,
+    heartbeat_interval: Option<u64>,
+    heartbeat_timeout: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub bind: String,
+    pub history_path: std::path::PathBuf,
+    pub accounts_path: std::path::PathBuf,
+    pub max_file_size: u64,
+    pub tls_cert: Option<std::path::PathBuf>,
+    pub tls_key: Option<std::path::PathBuf>,
+    pub heartbeat: Heartbeat,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub connect: String,
+    pub user: Option<String>,
+    pub register: bool,
+    pub downloads: std::path::PathBuf,
+    pub ca: Option<std::path::PathBuf>,
+    pub server_name: Option<String>,
+    pub keys: std::path::PathBuf,
+    pub heartbeat: Heartbeat,
+}
+
+/// When to ping a quiet peer and when to give up on it
+#[derive(Debug, Clone, Copy)]
+pub struct Heartbeat {
+    pub interval: std::time::Duration,
+    pub timeout: std::time::Duration,
+}
+
+impl Heartbeat {
+    /// The timeout has to leave the peer time to answer the ping
+    fn new(interval: Option<u64>, timeout: Option<u64>) -> Result<Heartbeat, std::io::Error> {
+        let interval = interval.unwrap_or(DEFAULT_HEARTBEAT_INTERVAL);
+        let timeout = timeout.unwrap_or(DEFAULT_HEARTBEAT_TIMEOUT);
+        if interval == 0 || timeout <= interval {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("The heartbeat timeout ({}s) has to be longer than the interval ({}s), which can not be 0", timeout, interval),
+            ));
+        }
+        Ok(Heartbeat {
+            interval: std::time::Duration::from_secs(interval),
+            timeout: std::time::Duration::from_secs(timeout),
+        })
+    }
+}
+
+pub enum Config {
+    Server(ServerConfig),
+    Client(ClientConfig),
+}
+
+fn default_config_path() -> Option<std::path::PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("teams").join("config.toml"))
+}
+
+/// Reads the config file. A missing file is fine unless it was given explicitly.
+fn load_config_file(path: Option<&std::path::Path>) -> Result<ConfigFile, std::io::Error> {
+    let (path, explicit) = match path {
+        Some(path) => (path.to_path_buf(), true),
+        None => match default_config_path() {
+            Some(path) => (path, false),
+            None => return Ok(ConfigFile::default()),
+        },
+    };
+
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && !explicit => {
+            log::info!("No config file at {:?}, use the defaults", path);
+            return Ok(ConfigFile::default());
+        },
+        Err(e) => return Err(e),
+    };
+    log::info!("Read config file {:?}", path);
+
+    toml::from_str(&content).map_err(|e| {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid config file {:?}: {}", path, e))
+    })
+}
+
+impl Cli {
+    /// Merges the command line with the config file, the command line wins
+    pub fn resolve(self) -> Result<Config, std::io::Error> {
+        let file = load_config_file(self.config.as_deref())?;
+
+        Ok(match self.command {
+            Mode::Server { bind, history, accounts, max_file_size, tls_cert, tls_key, heartbeat_interval, heartbeat_timeout } => Config::Server(ServerConfig {
+                bind: bind.or(file.server.bind).unwrap_or_else(|| DEFAULT_ADDRESS.to_string()),
+                history_path: history.or(file.server.history).unwrap_or_else(|| DEFAULT_HISTORY_PATH.into()),
+                accounts_path: accounts.or(file.server.accounts).unwrap_or_else(|| DEFAULT_ACCOUNTS_PATH.into()),
+                max_file_size: max_file_size.or(file.server.max_file_size).unwrap_or(DEFAULT_MAX_FILE_SIZE),
+                tls_cert: tls_cert.or(file.server.tls_cert),
+                tls_key: tls_key.or(file.server.tls_key),
+                heartbeat: Heartbeat::new(
+                    heartbeat_interval.or(file.server.heartbeat_interval),
+                    heartbeat_timeout.or(file.server.heartbeat_timeout),
+                )?,
+            }),
+            Mode::Client { connect, user, register, downloads, ca, server_name, keys, heartbeat_interval, heartbeat_timeout } => Config::Client(ClientConfig {
+                connect: connect.or(file.client.connect).unwrap_or_else(|| DEFAULT_ADDRESS.to_string()),
+                user: user.or(file.client.user),
+                register,
+                downloads: downloads.or(file.client.downloads)
+                    .or_else(dirs::download_dir)
+                    .unwrap_or_else(|| FALLBACK_DOWNLOADS_PATH.into()),
+                ca: ca.or(file.client.ca),
+                server_name: server_name.or(file.client.server_name),
+                keys: keys.or(file.client.keys)
+                    .or_else(|| dirs::config_dir().map(|dir| dir.join("teams").join("keys")))
+                    .unwrap_or_else(|| FALLBACK_KEYS_PATH.into()),
+                heartbeat: Heartbeat::new(
+                    heartbeat_interval.or(file.client.heartbeat_interval),
+                    heartbeat_timeout.or(file.client.heartbeat_timeout),
+                )?,
+            }),
+        })
+    }
+}
@@ -3,13 +3,21 @@ use image::{GenericImageView};
 fn get_str_ascii(intent :u8)-> &'static str{
     let index = intent/32;
     let ascii = [" ",".",",","-","~","+","=","@"];
-    return ascii[index as usize];
+    ascii[index as usize]
 }
 
-fn get_image(dir: &str, scale: u32) -> String {
+/// Smallest scale for which the ascii art of the image is at most `max_width` characters wide
+pub fn scale_for_width(dir: &str, max_width: u32) -> Result<u32, image::ImageError> {
+    let (width, _) = image::image_dimensions(dir)?;
+    Ok(width.div_ceil(max_width.max(1)).max(1))
+}
+
+/// Every `scale`th pixel of a row becomes one character, only every second row is used
+/// because characters are about twice as high as wide
+pub fn get_image(dir: &str, scale: u32) -> Result<String, image::ImageError> {
     let mut result = String::from("");
 
-    let img = image::open(dir).unwrap();
+    let img = image::open(dir)?;
     log::info!("{:?}", img.dimensions());
     let (width,height) = img.dimensions();
     for y in 0..height {
@@ -20,14 +28,14 @@ fn get_image(dir: &str, scale: u32) -> String {
                 if pix[3] == 0 {
                     intent = 0;
                 }
-                result.push(get_str_ascii(intent));
+                result.push_str(get_str_ascii(intent));
             }
         }
 
         if y%(scale*2)==0{
-            result.push("\n");
+            result.push('\n');
         }
     }
 
-    return result;
+    Ok(result)
 }
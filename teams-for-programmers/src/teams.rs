@@ -1,94 +1,575 @@
-use std::io::{BufRead, Write};
-use std::net::TcpStream;
+use std::io::{Read, Write};
 use serde::{Deserialize, Serialize};
 
 pub mod client;
 pub mod server;
+mod transport;
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+/// Starts a fenced code block, at the beginning of a line
+const CODE_FENCE: &str = "```";
+
+/// Byte index of the first code fence at the start of a line, leading spaces are allowed
+fn find_fence(text: &str) -> Option<usize> {
+    let mut line_start = 0;
+    for line in text.split_inclusive('\n') {
+        if line.trim_start_matches(' ').starts_with(CODE_FENCE) {
+            return Some(line_start + line.len() - line.trim_start_matches(' ').len());
+        }
+        line_start += line.len();
+    }
+    None
+}
+
+/// Whether the text has a fenced code block. Client and server decide with this if a message is Code.
+fn contains_code(text: &str) -> bool {
+    find_fence(text).is_some()
+}
+
+/// What the text of a message is, so that it can be rendered and stored accordingly
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum MessageKind {
+    #[default]
+    Text,
+    /// Contains at least one fenced ``` code block, whitespace is significant
+    Code,
+    /// Ascii art of an image, one line per row, shown as is
+    Image,
+    /// Only the two users of a direct conversation can read it, the encrypted payload holds the real kind
+    Encrypted,
+}
+
+/// A direct message. The client sends it with the recipient in `user`,
+/// the server delivers it with the sender in `user` and fills in id and timestamp.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 struct Message {
     user: String,
     message: String,
+    #[serde(default)]
+    kind: MessageKind,
+    #[serde(default)]
+    id: u64,
+    /// Seconds since the unix epoch
+    #[serde(default)]
+    timestamp: u64,
+    /// Chosen by the sending client to match the Ack, never delivered to the recipients
+    #[serde(default)]
+    local_id: u64,
+    /// The message this one replies to in a thread. The server always sets the first message of the thread.
+    #[serde(default)]
+    parent: Option<u64>,
+}
+
+/// A message to a channel. `user` is the sender, it is filled in by the server like id and timestamp.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+struct ChannelMessage {
+    channel: String,
+    user: String,
+    message: String,
+    #[serde(default)]
+    kind: MessageKind,
+    #[serde(default)]
+    id: u64,
+    #[serde(default)]
+    timestamp: u64,
+    #[serde(default)]
+    local_id: u64,
+    #[serde(default)]
+    parent: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ChannelInfo {
+    name: String,
+    members: usize,
+    joined: bool,
+}
+
+/// A conversation as seen by one user: either with another user or in a channel
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+enum Conversation {
+    Direct(String),
+    Channel(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct HistoryEntry {
+    id: u64,
+    user: String,
+    message: String,
+    kind: MessageKind,
+    /// Seconds since the unix epoch
+    timestamp: u64,
+    /// The sender changed the text after sending it
+    #[serde(default)]
+    edited: bool,
+    #[serde(default)]
+    reactions: Vec<Reaction>,
+    /// Number of replies in the thread started by this message
+    #[serde(default)]
+    replies: usize,
+}
+
+/// Everybody who reacted to a message with the emoji, in the order they did
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct Reaction {
+    emoji: String,
+    users: Vec<String>,
+}
+
+/// Announces a file. The client sends it with the recipient in `user`,
+/// the server forwards it with the sender in `user`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct FileOffer {
+    /// Chosen by the sender, identifies the transfer in every following message
+    id: u64,
+    user: String,
+    /// File name without any directories
+    name: String,
+    size: u64,
+    /// Hex encoded sha256 of the content
+    checksum: String,
+}
+
+/// Part of an accepted file, sent in order without gaps
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct FileChunk {
+    id: u64,
+    offset: u64,
+    #[serde(with = "base64_bytes")]
+    data: Vec<u8>,
+}
+
+/// Bytes as base64 string, a json array of numbers would be about four times as big
+mod base64_bytes {
+    use base64::Engine;
+
+    pub fn serialize<S: serde::Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::engine::general_purpose::STANDARD.encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let encoded = <String as serde::Deserialize>::deserialize(deserializer)?;
+        base64::engine::general_purpose::STANDARD.decode(encoded).map_err(serde::de::Error::custom)
+    }
+}
+
+/// What became of a Message or ChannelMessage a client sent
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum SendResult {
+    Stored {
+        id: u64,
+        timestamp: u64,
+    },
+    Rejected(String),
+}
+
+/// How a client proves who it is when it logs in
+#[derive(Serialize, Deserialize, Debug, Clone)]
+enum Credentials {
+    /// Creates the account, the username must not be registered yet
+    Register {
+        user: String,
+        password: String,
+    },
+    Password {
+        user: String,
+        password: String,
+    },
+    /// The session token of an earlier login, e.g. to reconnect without asking for the password again
+    Token {
+        user: String,
+        token: String,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+enum LoginReply {
+    /// Holds the session token for the next login
+    Accepted(String),
+    Rejected(String),
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 enum TeamsMessage  {
+    /// First message of every client, it may try again after a rejection
+    Login(Credentials),
+    LoginReply(LoginReply),
+    /// Sent by the server to tell everybody else that the user logged in
     NewUser(String),
+    /// Sent by the client to leave, sent by the server to tell everybody else that the user is offline
     UserExit(String),
+    ListUsers,
+    ChangeNick(String),
+    /// Sent to everybody, including the renamed user, after a successful ChangeNick
+    UserRenamed {
+        old: String,
+        new: String,
+    },
+    /// Reply to ListUsers with all connected users, including the asking one
+    UserList(Vec<String>),
     Message(Message),
+    /// Reply to every Message and ChannelMessage. A message sent again with the same `local_id`,
+    /// e.g. after a reconnect, is acknowledged again but only stored once.
+    Ack {
+        local_id: u64,
+        result: SendResult,
+    },
+    /// Replaces the text of an own message, only the sender may do so. Once stored, the server sends it
+    /// to everybody in the conversation, including the sender, and queues it for those who are offline.
+    EditMessage(u64, String),
+    /// Removes an own message from the conversation, authorised and relayed like EditMessage
+    DeleteMessage(u64),
+    /// Adds a reaction of the user to a message, anybody in its conversation may react
+    React(u64, String),
+    Unreact(u64, String),
+    /// All reactions to the message after one of them changed, sent like an EditMessage
+    Reactions {
+        id: u64,
+        reactions: Vec<Reaction>,
+    },
+    /// Reply to a Message whose recipient is offline, it gets delivered when they enter again
+    UserOffline(String),
+    CreateChannel(String),
+    JoinChannel(String),
+    LeaveChannel(String),
+    ListChannels,
+    ChannelMessage(ChannelMessage),
+    /// Reply to every successful channel request
+    ChannelList(Vec<ChannelInfo>),
+    /// Asks for up to `limit` stored messages older than the message with the id `before`.
+    /// Without `thread` these are the messages outside of threads, otherwise the replies to that message.
+    HistoryRequest {
+        conversation: Conversation,
+        before: Option<u64>,
+        limit: usize,
+        #[serde(default)]
+        thread: Option<u64>,
+    },
+    /// Reply to HistoryRequest, oldest message first. Empty if there is nothing older.
+    History {
+        conversation: Conversation,
+        entries: Vec<HistoryEntry>,
+        #[serde(default)]
+        thread: Option<u64>,
+    },
+    /// Offers a file to another user, who has to be online
+    FileOffer(FileOffer),
+    /// Sent by the recipient of an offer and forwarded to the sender, who starts sending the chunks
+    FileAccept(u64),
+    FileChunk(FileChunk),
+    /// Declines, aborts or rejects a transfer. Sent by either side and forwarded to the other one,
+    /// or by the server to both if a limit is exceeded or one of them left.
+    FileCancel {
+        id: u64,
+        reason: String,
+    },
+    /// The client sends the typing events and receipts with an empty `user`. The server relays them
+    /// to the other users of the conversation with the sender in `user` and the conversation as they see it.
+    Typing {
+        user: String,
+        conversation: Conversation,
+    },
+    StoppedTyping {
+        user: String,
+        conversation: Conversation,
+    },
+    /// The message with the id was shown to `user`
+    Read {
+        user: String,
+        conversation: Conversation,
+        id: u64,
+    },
+    /// The public key for end-to-end encrypted direct messages, sent by the client after every login
+    PublishKey(String),
+    /// Asks for the public key of the user
+    KeyRequest(String),
+    /// Reply to KeyRequest, None if the user never published a key
+    PublicKey {
+        user: String,
+        key: Option<String>,
+    },
+    /// Sent by either side when the other one was quiet for a while, answered with Pong
+    Ping,
+    Pong,
+    /// Reply to a request the server could not fulfill
+    Error(String),
 }
 
-fn try_recv(stream: &TcpStream) -> Result<Option<TeamsMessage>, std::io::Error> {
-    let mut reader = std::io::BufReader::new(stream);
-    let received_bytes: &[u8];
-    match reader.fill_buf() {
-        Ok(received) => received_bytes = received,
-        Err(e) => return match e.kind() {
-            std::io::ErrorKind::WouldBlock => Ok(None),
-            std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::ConnectionReset => {
-                    log::error!("Connection lost!");
-                    Err(e)
-                },
-            _ => Err(e),
+/// Upper bound for a single frame, so a broken peer can not make us allocate gigabytes.
+const MAX_FRAME_LEN: usize = 16 * 1024 * 1024;
+const FRAME_HEADER_LEN: usize = 4;
+
+/// Plain tcp or tls, depending on the config
+type Stream = Box<dyn transport::Transport>;
+
+/// A stream together with the bytes already read from it but not yet consumed.
+/// Every frame on the wire is a big endian u32 length followed by that many bytes of json.
+struct Connection {
+    stream: Stream,
+    buffer: Vec<u8>,
+}
+
+impl Connection {
+    fn new(stream: Stream) -> Connection {
+        Connection { stream, buffer: Vec::new() }
+    }
+}
+
+fn connection_error(e: std::io::Error) -> std::io::Error {
+    match e.kind() {
+        std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::ConnectionReset => {
+            log::error!("Connection lost!");
+            e
         },
+        _ => e,
     }
-    let _len = received_bytes.len();
-    let deserialized_message: TeamsMessage = match serde_json::from_slice(&received_bytes) {
-        Ok(m) => m,
-        Err(e) => {
-            if e.classify() == serde_json::error::Category::Eof {
+}
+
+/// Reads once from the stream into the connection buffer. Returns false if it would block.
+fn fill_buffer(connection: &mut Connection) -> Result<bool, std::io::Error> {
+    let mut chunk = [0u8; 4096];
+    loop {
+        match connection.stream.read(&mut chunk) {
+            Ok(0) => {
                 log::info!("EOF found!");
-                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, e));
-            }
-            log::error!("Could not deserialize {:?}", e);
-            return Ok(None);
-        },
-    };
-    log::info!("Request: {:?}", deserialized_message);
+                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, "EOF found"));
+            },
+            Ok(n) => {
+                connection.buffer.extend_from_slice(&chunk[..n]);
+                return Ok(true);
+            },
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::WouldBlock => return Ok(false),
+                std::io::ErrorKind::Interrupted => continue,
+                _ => return Err(connection_error(e)),
+            },
+        }
+    }
+}
 
-    Ok(Some(deserialized_message))
+/// Takes the next complete frame out of the buffer, if there is one.
+fn next_frame(connection: &mut Connection) -> Result<Option<Vec<u8>>, std::io::Error> {
+    if connection.buffer.len() < FRAME_HEADER_LEN {
+        return Ok(None);
+    }
+    let mut header = [0u8; FRAME_HEADER_LEN];
+    header.copy_from_slice(&connection.buffer[..FRAME_HEADER_LEN]);
+    let len = u32::from_be_bytes(header) as usize;
+    if len > MAX_FRAME_LEN {
+        log::error!("Frame with {} bytes is too big!", len);
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Frame too big"));
+    }
+    if connection.buffer.len() < FRAME_HEADER_LEN + len {
+        return Ok(None);
+    }
+    let frame = connection.buffer[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len].to_vec();
+    connection.buffer.drain(..FRAME_HEADER_LEN + len);
+    Ok(Some(frame))
 }
 
-fn recv(stream: &TcpStream) -> Result<Option<TeamsMessage>, std::io::Error> {
-    let mut reader = std::io::BufReader::new(stream);
-    let received: &[u8] = match reader.fill_buf() {
-        Ok(r) => r,
-        Err(e) => return match e.kind() {
-            std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::ConnectionReset => {
-                log::error!("Connection lost!");
-                Err(e)
-            },
-            _ => Err(e),
+fn deserialize(frame: &[u8]) -> Option<TeamsMessage> {
+    match serde_json::from_slice(frame) {
+        Ok(m) => {
+            log::info!("Request: {:?}", m);
+            Some(m)
         },
-    };
-    let deserialized_message: TeamsMessage = match serde_json::from_slice(&received) {
-        Ok(m) => m,
         Err(e) => {
-            if e.classify() == serde_json::error::Category::Eof {
-                log::info!("EOF found!");
-                return Err(std::io::Error::new(std::io::ErrorKind::ConnectionReset, e));
-            }
             log::error!("Could not deserialize {:?}", e);
-            return Ok(None);
+            None
         },
-    };
-    log::info!("Request: {:?}", deserialized_message);
+    }
+}
 
-    Ok(Some(deserialized_message))
+/// Non-blocking receive. Ok(None) means that there is no complete message yet
+/// (or that the message could not be deserialized).
+fn try_recv(connection: &mut Connection) -> Result<Option<TeamsMessage>, std::io::Error> {
+    loop {
+        if let Some(frame) = next_frame(connection)? {
+            return Ok(deserialize(&frame));
+        }
+        if !fill_buffer(connection)? {
+            return Ok(None);
+        }
+    }
 }
 
-fn send(message: &TeamsMessage, stream: &mut TcpStream) -> Result<(), std::io::Error> {
+/// Waits for the next message until the deadline, the stream has to be non-blocking for it to hold.
+/// Ok(None) means the message type is not known.
+fn recv(connection: &mut Connection, deadline: std::time::Instant) -> Result<Option<TeamsMessage>, std::io::Error> {
+    loop {
+        if let Some(frame) = next_frame(connection)? {
+            return Ok(deserialize(&frame));
+        }
+        if !fill_buffer(connection)? {
+            if std::time::Instant::now() >= deadline {
+                return Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "No answer in time"));
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+}
+
+/// Serializes the message into a frame, ready to be written
+fn frame(message: &TeamsMessage) -> Result<Vec<u8>, std::io::Error> {
     let serialized_response = serde_json::to_string(&message).expect("Could not serialize response!");
     log::info!("Send response: {}", serialized_response);
     let bytes = serialized_response.as_bytes();
-    match stream.write(bytes) {
-        Ok(_) => Ok(()),
-        Err(e) => match e.kind() {
-            std::io::ErrorKind::ConnectionAborted | std::io::ErrorKind::ConnectionReset => {
-                log::error!("Connection lost!");
-                Err(e)
+    if bytes.len() > MAX_FRAME_LEN {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Message too big"));
+    }
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + bytes.len());
+    frame.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    frame.extend_from_slice(bytes);
+    Ok(frame)
+}
+
+fn send(message: &TeamsMessage, stream: &mut Stream) -> Result<(), std::io::Error> {
+    let frame = frame(message)?;
+    let mut written = 0;
+    while written < frame.len() {
+        match stream.write(&frame[written..]) {
+            Ok(0) => return Err(std::io::Error::new(std::io::ErrorKind::WriteZero, "Could not write frame")),
+            Ok(n) => written += n,
+            Err(e) => match e.kind() {
+                // The client uses a non-blocking stream, so just wait until the frame is out
+                std::io::ErrorKind::WouldBlock => std::thread::sleep(std::time::Duration::from_millis(1)),
+                std::io::ErrorKind::Interrupted => {},
+                _ => return Err(connection_error(e)),
+            },
+        }
+    }
+    // Tls keeps the last records until they are flushed
+    loop {
+        match stream.flush() {
+            Ok(()) => return Ok(()),
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::WouldBlock => std::thread::sleep(std::time::Duration::from_millis(1)),
+                std::io::ErrorKind::Interrupted => {},
+                _ => return Err(connection_error(e)),
             },
-            _ => Err(e),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out the scripted reads one by one, None stands for a read that would block
+    struct Reads(std::collections::VecDeque<Option<Vec<u8>>>);
+
+    impl Read for Reads {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            match self.0.pop_front() {
+                Some(Some(mut part)) => {
+                    let n = part.len().min(buf.len());
+                    buf[..n].copy_from_slice(&part[..n]);
+                    if n < part.len() {
+                        self.0.push_front(Some(part.split_off(n)));
+                    }
+                    Ok(n)
+                },
+                _ => Err(std::io::Error::from(std::io::ErrorKind::WouldBlock)),
+            }
+        }
+    }
+
+    impl Write for Reads {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl transport::Transport for Reads {
+        fn set_nonblocking(&self, _nonblocking: bool) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+
+        fn shutdown(&mut self) -> Result<(), std::io::Error> {
+            Ok(())
+        }
+    }
+
+    fn connection(reads: Vec<Option<Vec<u8>>>) -> Connection {
+        Connection::new(Box::new(Reads(reads.into())))
+    }
+
+    fn new_user(name: &str) -> Vec<u8> {
+        frame(&TeamsMessage::NewUser(name.to_string())).unwrap()
+    }
+
+    fn is_new_user(message: Option<TeamsMessage>, name: &str) -> bool {
+        matches!(message, Some(TeamsMessage::NewUser(user)) if user == name)
+    }
+
+    #[test]
+    fn two_frames_in_one_read() {
+        let mut connection = connection(vec![Some([new_user("alice"), new_user("bob")].concat())]);
+        assert!(is_new_user(try_recv(&mut connection).unwrap(), "alice"));
+        assert!(is_new_user(try_recv(&mut connection).unwrap(), "bob"));
+        assert!(try_recv(&mut connection).unwrap().is_none());
+        assert!(connection.buffer.is_empty());
+    }
+
+    #[test]
+    fn frame_split_across_reads() {
+        let bytes = new_user("alice");
+        // The header itself arrives in two parts
+        let mut connection = connection(vec![
+            Some(bytes[..2].to_vec()),
+            None,
+            Some(bytes[2..7].to_vec()),
+            None,
+            Some(bytes[7..].to_vec()),
+        ]);
+        assert!(try_recv(&mut connection).unwrap().is_none());
+        assert_eq!(connection.buffer.len(), 2);
+        assert!(try_recv(&mut connection).unwrap().is_none());
+        assert_eq!(connection.buffer.len(), 7);
+        assert!(is_new_user(try_recv(&mut connection).unwrap(), "alice"));
+        assert!(connection.buffer.is_empty());
+    }
+
+    #[test]
+    fn frame_and_a_half() {
+        let bytes = [new_user("alice"), new_user("bob")].concat();
+        let cut = bytes.len() - 3;
+        let mut connection = connection(vec![Some(bytes[..cut].to_vec()), None, Some(bytes[cut..].to_vec())]);
+        assert!(is_new_user(try_recv(&mut connection).unwrap(), "alice"));
+        assert!(try_recv(&mut connection).unwrap().is_none());
+        assert!(is_new_user(try_recv(&mut connection).unwrap(), "bob"));
+    }
+
+    #[test]
+    fn oversized_length_header() {
+        let header = (MAX_FRAME_LEN as u32 + 1).to_be_bytes().to_vec();
+        let mut connection = connection(vec![Some(header)]);
+        let error = try_recv(&mut connection).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        // Already the header is refused, without waiting for the body
+        assert!(next_frame(&mut connection).is_err());
+    }
+
+    #[test]
+    fn code_fences_start_a_line() {
+        for text in ["```\ncode\n```", "look:\n```rust\nfn main() {}", "  ```sh\nls"] {
+            assert!(contains_code(text), "{}", text);
+        }
+        for text in ["plain text", "use ``` for code", "inline ```let a = 1;```", "a\n `` b"] {
+            assert!(!contains_code(text), "{}", text);
+        }
+        assert_eq!(find_fence("a\n  ```"), Some(4));
+    }
+
+    #[test]
+    fn largest_allowed_length_header_waits_for_the_body() {
+        let header = (MAX_FRAME_LEN as u32).to_be_bytes().to_vec();
+        let mut connection = connection(vec![Some(header)]);
+        assert!(try_recv(&mut connection).unwrap().is_none());
+        assert_eq!(next_frame(&mut connection).unwrap(), None);
+    }
+}
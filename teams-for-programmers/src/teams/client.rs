@@ -5,90 +5,1339 @@ use crossterm::style::{Color, Print, ResetColor, SetBackgroundColor, SetForegrou
 use crossterm::terminal::{Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
 use text2art::{BasicFonts, Font, Printer};
 
+mod code;
+mod commands;
+mod e2e;
+mod editor;
+mod markdown;
+mod transfer;
+
+/// The message box grows with the input up to this many lines
+const MAX_INPUT_LINES: usize = 6;
+
 enum Command {
     NewMessage(super::TeamsMessage),
     Input(String),
+    /// Move the selection in the chat list by the given amount
+    Select(isize),
+    /// Scroll the chat messages up by the given amount of lines, negative is down
+    Scroll(isize),
+    /// Move the message selection by the given amount, negative is older. 0 clears the selection.
+    SelectMessage(isize),
+    /// Add the reaction to the selected message, or take it back if it is already there
+    React(String),
+    /// A request from the input thread that the main thread only has to send
+    Send(super::TeamsMessage),
+    Quit,
     Reinit,
 }
 
-fn setup_username(connection: &mut std::net::TcpStream) -> String {
-    println!("Please choose a username:");
-    let mut username = String::new();
-    std::io::stdin().read_line(&mut username).unwrap();
-    let trimmed_username = username.trim();
-    if "" == trimmed_username {
-        println!("The username needs to be something, are you trying edge cases here???");
-        std::process::exit(1);
+/// Sends the login and waits for the answer of the server, at most LOGIN_TIMEOUT.
+/// The stream has to be blocking, it is again afterwards.
+fn log_in(connection: &mut super::Connection, credentials: super::Credentials) -> Result<super::LoginReply, std::io::Error> {
+    super::send(&super::TeamsMessage::Login(credentials), &mut connection.stream)?;
+
+    // A server that accepted the connection but does not answer must not hang the client
+    connection.stream.set_nonblocking(true)?;
+    let deadline = std::time::Instant::now() + LOGIN_TIMEOUT;
+    let reply = loop {
+        match super::recv(connection, deadline) {
+            Ok(Some(super::TeamsMessage::LoginReply(reply))) => break Ok(reply),
+            Ok(Some(m)) => log::warn!("Expected the login reply, got {:?}", m),
+            Ok(None) => {},
+            Err(e) => break Err(e),
+        }
+    };
+    connection.stream.set_nonblocking(false)?;
+    reply
+}
+
+/// Logs in with the configured username, or asks for one, until the server accepts the password.
+/// The server closes connections that take too long or fail too often, then the login is sent again on a new one.
+/// Returns the connection, the username and the session token for reconnecting.
+fn setup_account(
+    connector: &super::transport::Connector,
+    address: &str,
+    mut connection: super::Connection,
+    configured: Option<String>,
+    register: bool,
+) -> Result<(super::Connection, String, String), std::io::Error> {
+    let mut configured = configured;
+    if configured.is_none() {
+        println!("Please choose a username:");
+    }
+    loop {
+        let username = match configured.take() {
+            Some(username) => username,
+            None => {
+                let mut username = String::new();
+                std::io::stdin().read_line(&mut username).unwrap();
+                username
+            },
+        };
+        let trimmed_username = username.trim().to_string();
+        if trimmed_username.is_empty() {
+            println!("The username needs to be something, are you trying edge cases here???");
+            continue;
+        }
+
+        let password = rpassword::read_password_from_tty(Some(&format!("Password for {}: ", trimmed_username)))
+            .expect("Could not read the password!");
+        let credentials = match register {
+            true => {
+                let repeated = rpassword::read_password_from_tty(Some("Repeat the password: ")).expect("Could not read the password!");
+                if repeated != password {
+                    println!("The passwords do not match, please try again.");
+                    configured = Some(trimmed_username);
+                    continue;
+                }
+                super::Credentials::Register { user: trimmed_username.clone(), password }
+            },
+            false => super::Credentials::Password { user: trimmed_username.clone(), password },
+        };
+
+        let reply = match log_in(&mut connection, credentials.clone()) {
+            Err(e) if e.kind() != std::io::ErrorKind::TimedOut => {
+                log::info!("The connection is gone, log in on a new one {:?}", e);
+                connection = super::Connection::new(connector.connect(address)?);
+                log_in(&mut connection, credentials)?
+            },
+            reply => reply?,
+        };
+        match reply {
+            super::LoginReply::Accepted(token) => return Ok((connection, trimmed_username, token)),
+            super::LoginReply::Rejected(reason) => {
+                println!("{}, please choose a username again:", reason);
+            },
+        }
     }
-    let message = super::TeamsMessage::NewUser(trimmed_username.to_string());
+}
 
-    super::send(&message, connection).unwrap();
+/// Lines scrolled per PageUp / PageDown
+const SCROLL_STEP: usize = 10;
+const HISTORY_PAGE_SIZE: usize = 50;
+/// While typing, Typing is sent again after this long so the peers do not expire it
+const TYPING_REFRESH: std::time::Duration = std::time::Duration::from_secs(3);
+/// Without a key press for this long the user stopped typing
+const TYPING_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+/// A typing peer is forgotten after this long without a refresh, in case StoppedTyping got lost
+const TYPING_EXPIRY: std::time::Duration = std::time::Duration::from_secs(7);
+/// Time the server has to answer a login
+const LOGIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+/// Time the server has to answer a ping before it counts as silent
+const PONG_GRACE: std::time::Duration = std::time::Duration::from_secs(3);
+/// Reactions on Alt+1 to Alt+5
+const QUICK_REACTIONS: [&str; 5] = ["👍", "🎉", "😂", "👀", "🚀"];
 
-    trimmed_username.to_string()
+/// One message in a conversation buffer
+#[derive(PartialEq, Eq)]
+enum LineKind {
+    Message,
+    /// Feedback of the client itself, like command errors. Never sent anywhere.
+    Notice,
 }
 
-fn draw(frame: &mut tui::terminal::Frame<tui::backend::CrosstermBackend<std::io::Stdout>>) -> () {
-    let top_chunks = tui::layout::Layout::default()
-        .margin(1)
-        .direction(tui::layout::Direction::Horizontal)
+/// Whether the server acknowledged an own message
+enum SendState {
+    Pending,
+    Sent,
+    Failed(String),
+}
+
+/// The connection to the server as the heartbeats see it
+#[derive(Default, Clone, Copy, PartialEq, Eq)]
+enum ConnectionStatus {
+    #[default]
+    Connected,
+    /// Nothing arrived for this long, not even the answer to a ping
+    Silent(std::time::Duration),
+    Reconnecting,
+}
+
+struct ChatLine {
+    kind: LineKind,
+    /// How the message is rendered
+    content: super::MessageKind,
+    /// None for own messages and notices, the server only tells the recipients about the id
+    id: Option<u64>,
+    user: String,
+    message: String,
+    /// Seconds since the unix epoch
+    timestamp: u64,
+    /// Received while the conversation was not shown, no read receipt was sent yet
+    unread: bool,
+    /// The sender changed the text after sending it
+    edited: bool,
+    reactions: Vec<super::Reaction>,
+    /// Number of replies in the thread this message started
+    replies: usize,
+    /// Sent end-to-end encrypted, the message holds the decrypted text
+    encrypted: bool,
+    /// Only set for own messages sent in this session, with the id chosen to match the acknowledgement
+    sending: Option<(u64, SendState)>,
+    /// The rendered rows together with the width and username they were rendered for
+    rendered: Option<(usize, String, Vec<tui::text::Spans<'static>>)>,
+}
+
+impl ChatLine {
+    fn new(kind: LineKind, content: super::MessageKind, id: Option<u64>, user: String, message: String, timestamp: u64) -> ChatLine {
+        ChatLine { kind, content, id, user, message, timestamp, unread: false, edited: false, reactions: vec![], replies: 0, encrypted: false, sending: None, rendered: None }
+    }
+
+    /// Renders the line, highlighting code is expensive so the result is kept until the width changes
+    fn rows(&mut self, username: &str, width: usize) -> &[tui::text::Spans<'static>] {
+        let outdated = match &self.rendered {
+            Some((rendered_width, rendered_username, _)) => *rendered_width != width || rendered_username != username,
+            None => true,
+        };
+        if outdated {
+            self.rendered = Some((width, username.to_string(), render_line(self, username, width)));
+        }
+        &self.rendered.as_ref().unwrap().2
+    }
+}
+
+#[derive(Default)]
+struct ConversationBuffer {
+    /// Oldest first
+    lines: Vec<ChatLine>,
+    history_requested: bool,
+    /// The server has no older messages
+    history_complete: bool,
+    /// Who read the message with the id, from the receipts of the other users
+    read_by: std::collections::HashMap<u64, Vec<String>>,
+    /// Other users typing right now with the time of their last Typing event
+    typing: Vec<(String, std::time::Instant)>,
+    /// Replies by the id of the message that started the thread. A thread is only loaded once it is opened.
+    threads: std::collections::HashMap<u64, ConversationBuffer>,
+}
+
+impl ConversationBuffer {
+    /// The lines of the conversation and of its threads
+    fn all_lines_mut(&mut self) -> impl Iterator<Item = &mut ChatLine> {
+        self.lines.iter_mut().chain(self.threads.values_mut().flat_map(|thread| thread.lines.iter_mut()))
+    }
+
+    /// Puts a page of history in front of the buffer, dropping what is already there.
+    /// Returns true if some of the messages were encrypted.
+    fn merge_history(&mut self, entries: Vec<super::HistoryEntry>, keys: Option<&e2e::KeyPair>, username: &str) -> Option<String> {
+        if entries.is_empty() {
+            self.history_complete = true;
+            return None;
+        }
+
+        self.lines.retain(|line| match line.id {
+            Some(id) => !entries.iter().any(|entry| entry.id == id),
+            None => line.kind == LineKind::Notice || !entries.iter().any(|entry| entry.user == line.user && entry.message == line.message),
+        });
+        let mut peer_key = None;
+        let mut lines: Vec<ChatLine> = entries.into_iter()
+            .map(|entry| {
+                let mut line = ChatLine::new(LineKind::Message, entry.kind, Some(entry.id), entry.user, entry.message, entry.timestamp);
+                line.edited = entry.edited;
+                line.reactions = entry.reactions;
+                line.replies = entry.replies;
+                if let Some(key) = decrypt_line(keys, username, &mut line) {
+                    peer_key = Some(key);
+                }
+                line
+            })
+            .collect();
+        lines.append(&mut self.lines);
+        self.lines = lines;
+        peer_key
+    }
+}
+
+/// Everything the render thread needs to know
+#[derive(Default)]
+struct State {
+    username: String,
+    channels: Vec<super::ChannelInfo>,
+    users: Vec<String>,
+    buffers: std::collections::HashMap<super::Conversation, ConversationBuffer>,
+    selected: Option<super::Conversation>,
+    /// Id of the message in the selected conversation that reactions go to
+    selected_message: Option<u64>,
+    /// Scroll the selected message into view with the next draw
+    reveal_selected: bool,
+    /// Rendered lines scrolled up from the bottom of the selected conversation
+    scroll: usize,
+    /// Set by the render thread, so the main thread knows when the top is reached
+    scroll_max: usize,
+    /// Inner width of the chat pane, set by the render thread
+    chat_width: usize,
+    /// Print the next list as notice, because the user asked for it with /channels or /who
+    show_channel_list: bool,
+    show_user_list: bool,
+    editor: editor::LineEditor,
+    status: String,
+    /// Running transfers by their id
+    uploads: std::collections::HashMap<u64, transfer::Upload>,
+    downloads: std::collections::HashMap<u64, transfer::Download>,
+    /// Last short number given to a transfer
+    transfer_count: usize,
+    /// Conversation the user is typing in, with the time of the last key press and of the last Typing sent
+    typing: Option<(super::Conversation, std::time::Instant, std::time::Instant)>,
+    /// Own key pair for encrypted direct messages, None if the key file could not be used
+    keys: Option<e2e::KeyPair>,
+    /// Public keys of other users the own user trusts
+    peer_keys: e2e::KnownKeys,
+    /// Keys of other users that are not trusted yet, only used once the user confirms them with /trust
+    changed_keys: std::collections::HashMap<String, String>,
+    /// Users whose direct messages are sent encrypted
+    encrypted: std::collections::HashSet<String>,
+    /// Shown in the title of the chat list
+    connection: ConnectionStatus,
+}
+
+impl State {
+    /// Joined channels first, then the direct conversations
+    fn conversations(&self) -> Vec<super::Conversation> {
+        let mut conversations: Vec<super::Conversation> = self.channels.iter()
+            .filter(|channel| channel.joined)
+            .map(|channel| super::Conversation::Channel(channel.name.clone()))
+            .collect();
+        let mut direct: Vec<super::Conversation> = self.buffers.keys()
+            .filter(|conversation| matches!(conversation, super::Conversation::Direct(_)))
+            .cloned()
+            .collect();
+        direct.sort_by_key(conversation_title);
+        conversations.append(&mut direct);
+        conversations
+    }
+
+    /// Selects the conversation and returns the history request to send if it was never opened before
+    fn open(&mut self, conversation: super::Conversation) -> Option<super::TeamsMessage> {
+        self.selected = Some(conversation.clone());
+        self.selected_message = None;
+        self.scroll = 0;
+        let buffer = self.buffers.entry(conversation.clone()).or_default();
+        if buffer.history_requested {
+            return None;
+        }
+        buffer.history_requested = true;
+        Some(super::TeamsMessage::HistoryRequest { conversation, before: None, limit: HISTORY_PAGE_SIZE, thread: None })
+    }
+
+    /// Moves the selection by `delta` in the conversation list
+    fn select_relative(&mut self, delta: isize) -> Option<super::TeamsMessage> {
+        let conversations = self.conversations();
+        if conversations.is_empty() {
+            return None;
+        }
+        let current = self.selected.as_ref()
+            .and_then(|selected| conversations.iter().position(|c| c == selected))
+            .map(|index| index as isize)
+            .unwrap_or(-delta.signum());
+        let next = (current + delta).rem_euclid(conversations.len() as isize) as usize;
+        self.open(conversations[next].clone())
+    }
+
+    /// Adds a message to a conversation, opening it if nothing is selected yet
+    fn push(&mut self, conversation: super::Conversation, line: ChatLine) -> Option<super::TeamsMessage> {
+        let buffer = self.buffers.entry(conversation.clone()).or_default();
+        buffer.typing.retain(|(user, _)| *user != line.user);
+        buffer.lines.push(line);
+        if self.selected.is_none() {
+            return self.open(conversation);
+        }
+        if self.selected.as_ref() == Some(&conversation) && self.scroll > 0 {
+            // Keep the view where it is while the user reads older messages
+            self.scroll += 1;
+        }
+        None
+    }
+
+    /// Adds a message of another user, replies go to their thread
+    fn receive(&mut self, conversation: super::Conversation, parent: Option<u64>, mut line: ChatLine) -> Option<super::TeamsMessage> {
+        match parent {
+            Some(parent) => {
+                self.push_reply(conversation, parent, line);
+                None
+            },
+            None => {
+                line.unread = true;
+                self.push(conversation, line)
+            },
+        }
+    }
+
+    /// Adds a reply to its thread and counts it at the message that started the thread
+    fn push_reply(&mut self, conversation: super::Conversation, parent: u64, line: ChatLine) {
+        let buffer = self.buffers.entry(conversation).or_default();
+        buffer.typing.retain(|(user, _)| *user != line.user);
+        buffer.threads.entry(parent).or_default().lines.push(line);
+        if let Some(root) = buffer.lines.iter_mut().find(|root| root.id == Some(parent)) {
+            root.replies += 1;
+        }
+    }
+
+    /// Follows a nick change of any user, including the own one
+    fn rename(&mut self, old: &str, new: &str) {
+        for user in self.users.iter_mut().filter(|user| user.as_str() == old) {
+            *user = new.to_string();
+        }
+        self.users.sort();
+
+        let old_conversation = super::Conversation::Direct(old.to_string());
+        let new_conversation = super::Conversation::Direct(new.to_string());
+        if let Some(buffer) = self.buffers.remove(&old_conversation) {
+            self.buffers.insert(new_conversation.clone(), buffer);
+        }
+        if self.selected.as_ref() == Some(&old_conversation) {
+            self.selected = Some(new_conversation);
+        }
+        for buffer in self.buffers.values_mut() {
+            for user in buffer.read_by.values_mut().flatten().filter(|user| user.as_str() == old) {
+                *user = new.to_string();
+            }
+            buffer.typing.retain(|(user, _)| user != old);
+        }
+        for line in self.buffers.values_mut().flat_map(|buffer| buffer.all_lines_mut()) {
+            for user in line.reactions.iter_mut().flat_map(|reaction| reaction.users.iter_mut()).filter(|user| user.as_str() == old) {
+                *user = new.to_string();
+            }
+        }
+        self.peer_keys.rename(old, new);
+        if let Some(key) = self.changed_keys.remove(old) {
+            self.changed_keys.insert(new.to_string(), key);
+        }
+        if self.encrypted.remove(old) {
+            self.encrypted.insert(new.to_string());
+        }
+        for upload in self.uploads.values_mut().filter(|upload| upload.user == old) {
+            upload.user = new.to_string();
+        }
+        for download in self.downloads.values_mut().filter(|download| download.user == old) {
+            download.user = new.to_string();
+        }
+    }
+
+    /// Shows the own message right away and returns the request that sends it.
+    /// With a parent it is a reply in the thread of that message.
+    fn send_text(&mut self, conversation: super::Conversation, parent: Option<u64>, text: String) -> Option<super::TeamsMessage> {
+        let kind = if super::contains_code(&text) { super::MessageKind::Code } else { super::MessageKind::Text };
+        self.send(conversation, parent, text, kind)
+    }
+
+    /// Returns None if the message could not be encrypted, the line shows why.
+    fn send(&mut self, conversation: super::Conversation, parent: Option<u64>, text: String, kind: super::MessageKind) -> Option<super::TeamsMessage> {
+        let local_id = new_local_id();
+        let mut line = ChatLine::new(LineKind::Message, kind, None, self.username.clone(), text, now());
+        line.encrypted = self.is_encrypted(&conversation);
+        let request = self.line_request(&conversation, parent, &line, local_id);
+        line.sending = match &request {
+            Ok(_) => Some((local_id, SendState::Pending)),
+            Err(reason) => Some((local_id, SendState::Failed(reason.clone()))),
+        };
+        match parent {
+            Some(parent) => self.push_reply(conversation, parent, line),
+            None => {
+                self.buffers.entry(conversation).or_default().lines.push(line);
+                self.scroll = 0;
+            },
+        }
+        request.ok()
+    }
+
+    fn is_encrypted(&self, conversation: &super::Conversation) -> bool {
+        matches!(conversation, super::Conversation::Direct(user) if self.encrypted.contains(user))
+    }
+
+    /// Encrypts the text for the other user of the direct conversation
+    fn encrypt(&self, user: &str, kind: super::MessageKind, text: &str) -> Result<String, String> {
+        let keys = self.keys.as_ref().ok_or_else(|| "You have no key to encrypt with".to_string())?;
+        let peer_key = match self.peer_keys.get(user) {
+            Some(peer_key) => peer_key,
+            None if self.changed_keys.contains_key(user) => return Err(format!("The key of {} is not trusted yet, compare its fingerprint with them and /trust it", user)),
+            None => return Err(format!("The key of {} is not known yet", user)),
+        };
+        keys.encrypt(peer_key, kind, text)
+    }
+
+    /// The request that sends the own line, encrypted if the line is
+    fn line_request(&self, conversation: &super::Conversation, parent: Option<u64>, line: &ChatLine, local_id: u64) -> Result<super::TeamsMessage, String> {
+        match conversation {
+            super::Conversation::Direct(user) if line.encrypted => {
+                let encrypted = self.encrypt(user, line.content, &line.message)?;
+                Ok(message_request(conversation, &line.user, &encrypted, super::MessageKind::Encrypted, local_id, parent))
+            },
+            _ => Ok(message_request(conversation, &line.user, &line.message, line.content, local_id, parent)),
+        }
+    }
+
+    /// Compares the key the peer uses with the trusted one and encrypts the messages to them from now on.
+    /// Any other key is not used until the user trusts it, the server or somebody in between might pretend to be them.
+    /// Only the key in the own history is trusted right away, if there is none yet.
+    fn learn_key(&mut self, user: &str, key: String, from_history: bool) {
+        let conversation = super::Conversation::Direct(user.to_string());
+        match self.peer_keys.get(user) {
+            Some(known) if *known == key => {
+                self.changed_keys.remove(user);
+            },
+            None if from_history => {
+                let text = format!("Messages with {} are end-to-end encrypted. Compare the fingerprint {} with them to be sure nobody is in between", user, e2e::fingerprint(&key));
+                self.notice_in(conversation, &text);
+                self.peer_keys.trust(user, key);
+            },
+            known => {
+                if self.changed_keys.get(user) != Some(&key) {
+                    let text = match known {
+                        Some(_) => format!(
+                            "!! The key of {} changed, its fingerprint is {} now. Messages to them stay encrypted with the old key. Make sure they really got a new key, then /trust it !!",
+                            user, e2e::fingerprint(&key),
+                        ),
+                        None => format!("The fingerprint of the key of {} is {}. Compare it with them, then /trust it to encrypt the messages to them", user, e2e::fingerprint(&key)),
+                    };
+                    self.notice_in(conversation, &text);
+                }
+                self.changed_keys.insert(user.to_string(), key);
+            },
+        }
+        self.encrypted.insert(user.to_string());
+    }
+
+    /// Uses the new key of the peer from now on
+    fn trust_key(&mut self, user: &str) {
+        match self.changed_keys.remove(user) {
+            Some(key) => {
+                let text = format!("Messages to {} are encrypted with the key {} from now on", user, e2e::fingerprint(&key));
+                self.notice_in(super::Conversation::Direct(user.to_string()), &text);
+                self.peer_keys.trust(user, key);
+                self.encrypted.insert(user.to_string());
+            },
+            None => self.notice(&format!("There is no new key of {} to trust", user)),
+        }
+    }
+
+    /// Marks the own message as sent or failed, returns the reason if it failed
+    fn acknowledge(&mut self, local_id: u64, result: super::SendResult) -> Option<String> {
+        let line = self.buffers.values_mut()
+            .flat_map(|buffer| buffer.all_lines_mut())
+            .find(|line| matches!(line.sending, Some((id, _)) if id == local_id))?;
+        match result {
+            super::SendResult::Stored { id, timestamp } => {
+                line.id = Some(id);
+                line.timestamp = timestamp;
+                line.sending = Some((local_id, SendState::Sent));
+                line.rendered = None;
+                None
+            },
+            super::SendResult::Rejected(reason) => {
+                line.sending = Some((local_id, SendState::Failed(reason.clone())));
+                Some(reason)
+            },
+        }
+    }
+
+    /// The newest own message in the conversation that was not rejected. It has no id until the server stored it.
+    fn last_own_message(&self, conversation: &super::Conversation) -> Option<&ChatLine> {
+        self.buffers.get(conversation)?.lines.iter()
+            .rev()
+            .filter(|line| !matches!(line.sending, Some((_, SendState::Failed(_)))))
+            .find(|line| line.kind == LineKind::Message && line.user == self.username)
+    }
+
+    /// Applies a confirmed edit, or a deletion if there is no text, to the message wherever it is shown
+    fn change_message(&mut self, id: u64, text: Option<String>) {
+        if text.is_none() && self.selected_message == Some(id) {
+            self.selected_message = None;
+        }
+        for buffer in self.buffers.values_mut() {
+            let line = match buffer.all_lines_mut().find(|line| line.id == Some(id)) {
+                Some(line) => line,
+                None => continue,
+            };
+            match text {
+                Some(text) if line.encrypted => {
+                    line.content = super::MessageKind::Encrypted;
+                    line.message = text;
+                    decrypt_line(self.keys.as_ref(), &self.username, &mut *line);
+                    line.edited = true;
+                    line.rendered = None;
+                },
+                Some(text) => {
+                    if line.content != super::MessageKind::Image {
+                        line.content = if super::contains_code(&text) { super::MessageKind::Code } else { super::MessageKind::Text };
+                    }
+                    line.message = text;
+                    line.edited = true;
+                    line.rendered = None;
+                },
+                None => {
+                    buffer.lines.retain(|line| line.id != Some(id));
+                    buffer.threads.remove(&id);
+                    buffer.read_by.remove(&id);
+                    let parent = buffer.threads.iter_mut()
+                        .find(|(_, thread)| thread.lines.iter().any(|line| line.id == Some(id)))
+                        .map(|(parent, thread)| {
+                            thread.lines.retain(|line| line.id != Some(id));
+                            *parent
+                        });
+                    if let Some(root) = buffer.lines.iter_mut().find(|root| parent.is_some() && root.id == parent) {
+                        root.replies = root.replies.saturating_sub(1);
+                    }
+                },
+            }
+            return;
+        }
+    }
+
+    /// Moves the selection over the messages the server stored, moving past the newest one clears it.
+    /// Returns the history request for the thread of the selected message if it was never opened before.
+    fn select_message(&mut self, delta: isize) -> Option<super::TeamsMessage> {
+        let ids: Vec<u64> = match self.selected.as_ref().and_then(|selected| self.buffers.get(selected)) {
+            Some(buffer) => buffer.lines.iter().filter_map(|line| line.id).collect(),
+            None => vec![],
+        };
+        let current = self.selected_message.and_then(|selected| ids.iter().position(|id| *id == selected));
+        let next = match (current, delta) {
+            (_, 0) => None,
+            (None, _) if delta > 0 => None,
+            (None, _) => ids.len().checked_sub(delta.unsigned_abs()),
+            (Some(index), _) => match index.checked_add_signed(delta) {
+                Some(next) => Some(next.min(ids.len())),
+                None => Some(0),
+            },
+        };
+        self.selected_message = next.and_then(|next| ids.get(next).copied());
+        self.reveal_selected = self.selected_message.is_some();
+
+        let (conversation, id) = (self.selected.clone()?, self.selected_message?);
+        let thread = self.buffers.entry(conversation.clone()).or_default().threads.entry(id).or_default();
+        if thread.history_requested {
+            return None;
+        }
+        thread.history_requested = true;
+        Some(super::TeamsMessage::HistoryRequest { conversation, before: None, limit: HISTORY_PAGE_SIZE, thread: Some(id) })
+    }
+
+    /// Toggles the own reaction on the selected message, or on the newest one if none is selected
+    fn reaction_request(&mut self, emoji: String) -> Option<super::TeamsMessage> {
+        let buffer = self.selected.as_ref().and_then(|selected| self.buffers.get(selected));
+        let line = buffer.and_then(|buffer| match self.selected_message {
+            Some(selected) => buffer.lines.iter().find(|line| line.id == Some(selected)),
+            None => buffer.lines.iter().rev().find(|line| line.id.is_some()),
+        });
+        let line = match line {
+            Some(line) => line,
+            None => {
+                self.notice("There is no message to react to, select one with Alt+Up");
+                return None;
+            },
+        };
+        let id = line.id.unwrap();
+        let reacted = line.reactions.iter().any(|reaction| reaction.emoji == emoji && reaction.users.contains(&self.username));
+        match reacted {
+            true => Some(super::TeamsMessage::Unreact(id, emoji)),
+            false => Some(super::TeamsMessage::React(id, emoji)),
+        }
+    }
+
+    fn set_reactions(&mut self, id: u64, reactions: Vec<super::Reaction>) {
+        if let Some(line) = self.buffers.values_mut().flat_map(|buffer| buffer.all_lines_mut()).find(|line| line.id == Some(id)) {
+            line.reactions = reactions;
+        }
+    }
+
+    /// Requests for the own messages the server did not acknowledge yet, oldest first per conversation
+    fn unacknowledged(&self) -> Vec<super::TeamsMessage> {
+        let mut requests = vec![];
+        for (conversation, buffer) in &self.buffers {
+            let threads = buffer.threads.iter().map(|(parent, thread)| (Some(*parent), &thread.lines));
+            for (parent, lines) in std::iter::once((None, &buffer.lines)).chain(threads) {
+                for line in lines {
+                    if let Some((local_id, SendState::Pending)) = line.sending {
+                        match self.line_request(conversation, parent, line, local_id) {
+                            Ok(request) => requests.push(request),
+                            Err(reason) => log::error!("Could not send again: {}", reason),
+                        }
+                    }
+                }
+            }
+        }
+        requests
+    }
+
+    /// Shows feedback in the selected conversation, or in the status line if there is none
+    fn notice(&mut self, text: &str) {
+        let selected = match self.selected.clone() {
+            Some(selected) => selected,
+            None => {
+                self.status = text.to_string();
+                return;
+            },
+        };
+        self.notice_in(selected, text);
+    }
+
+    fn notice_in(&mut self, conversation: super::Conversation, text: &str) {
+        if self.selected.as_ref() == Some(&conversation) {
+            self.scroll = 0;
+        }
+        self.buffers.entry(conversation).or_default().lines.push(ChatLine::new(LineKind::Notice, super::MessageKind::Text, None, String::new(), text.to_string(), now()));
+    }
+
+    /// Receipts for the unread messages of the selected conversation, they count as read once shown
+    fn read_receipts(&mut self) -> Vec<super::TeamsMessage> {
+        let selected = match self.selected.clone() {
+            Some(selected) => selected,
+            None => return vec![],
+        };
+        let buffer = match self.buffers.get_mut(&selected) {
+            Some(buffer) => buffer,
+            None => return vec![],
+        };
+        let mut receipts = vec![];
+        for line in buffer.lines.iter_mut().filter(|line| line.unread) {
+            line.unread = false;
+            if let Some(id) = line.id {
+                receipts.push(super::TeamsMessage::Read { user: String::new(), conversation: selected.clone(), id });
+            }
+        }
+        receipts
+    }
+
+    /// Decides from the message box whether the peers have to be told about typing.
+    /// Commands do not count as typing.
+    fn typing_update(&mut self, key_pressed: bool) -> Vec<super::TeamsMessage> {
+        let now = std::time::Instant::now();
+        let text = self.editor.text();
+        let typing_in = match &self.selected {
+            Some(selected) if !text.trim().is_empty() && !text.starts_with('/') => Some(selected.clone()),
+            _ => None,
+        };
+
+        let mut requests = vec![];
+        if let Some((conversation, last_key, _)) = &self.typing {
+            let stopped = typing_in.as_ref() != Some(conversation) || (!key_pressed && now.duration_since(*last_key) >= TYPING_TIMEOUT);
+            if stopped {
+                requests.push(super::TeamsMessage::StoppedTyping { user: String::new(), conversation: conversation.clone() });
+                self.typing = None;
+            }
+        }
+        if let (Some(conversation), true) = (typing_in, key_pressed) {
+            match &mut self.typing {
+                Some((_, last_key, last_sent)) => {
+                    *last_key = now;
+                    if now.duration_since(*last_sent) >= TYPING_REFRESH {
+                        *last_sent = now;
+                        requests.push(super::TeamsMessage::Typing { user: String::new(), conversation });
+                    }
+                },
+                None => {
+                    self.typing = Some((conversation.clone(), now, now));
+                    requests.push(super::TeamsMessage::Typing { user: String::new(), conversation });
+                },
+            }
+        }
+        requests
+    }
+
+    fn next_transfer_number(&mut self) -> usize {
+        self.transfer_count += 1;
+        self.transfer_count
+    }
+
+    /// Id of the upload or download with the short number
+    fn transfer_id(&self, number: usize) -> Option<u64> {
+        self.uploads.iter().find(|(_, upload)| upload.number == number).map(|(id, _)| *id)
+            .or_else(|| self.downloads.iter().find(|(_, download)| download.number == number).map(|(id, _)| *id))
+    }
+
+    /// Progress of the running transfers for the status line
+    fn transfer_status(&self) -> String {
+        let mut parts: Vec<(usize, String)> = self.uploads.values()
+            .map(|upload| (upload.number, format!("↑ {} {}%", upload.name, transfer::percent(upload.sent, upload.size))))
+            .chain(self.downloads.values()
+                .filter(|download| download.is_accepted())
+                .map(|download| (download.number, format!("↓ {} {}%", download.name, transfer::percent(download.received, download.size)))))
+            .collect();
+        parts.sort();
+        parts.into_iter().map(|(number, part)| format!("[{}] {}", number, part)).collect::<Vec<String>>().join("  ")
+    }
+
+    /// Marks the connection as lost. Only the first to notice gets true, so only one reconnect is started.
+    fn connection_lost(&mut self) -> bool {
+        let first = self.connection != ConnectionStatus::Reconnecting;
+        self.connection = ConnectionStatus::Reconnecting;
+        first
+    }
+
+    /// Forgets every transfer, the server cancels them anyway when the connection is lost
+    fn abort_transfers(&mut self, reason: &str) {
+        for upload in std::mem::take(&mut self.uploads).into_values() {
+            self.notice_in(super::Conversation::Direct(upload.user), &format!("Sending {} failed: {}", upload.name, reason));
+        }
+        for download in std::mem::take(&mut self.downloads).into_values() {
+            self.notice_in(super::Conversation::Direct(download.user.clone()), &format!("Receiving {} failed: {}", download.name, reason));
+            download.abort();
+        }
+    }
+
+    /// Scrolls the selected conversation, returns a request for older messages when scrolling past the top
+    fn scroll_by(&mut self, delta: isize) -> Option<super::TeamsMessage> {
+        let at_top = self.scroll >= self.scroll_max;
+        self.scroll = (self.scroll as isize + delta).clamp(0, self.scroll_max as isize) as usize;
+        if delta <= 0 || !at_top {
+            return None;
+        }
+
+        let conversation = self.selected.clone()?;
+        let buffer = self.buffers.get(&conversation)?;
+        if buffer.history_complete {
+            self.status = "!! No older messages !!".to_string();
+            return None;
+        }
+        let before = buffer.lines.iter().find_map(|line| line.id);
+        Some(super::TeamsMessage::HistoryRequest { conversation, before, limit: HISTORY_PAGE_SIZE, thread: None })
+    }
+}
+
+fn message_request(conversation: &super::Conversation, username: &str, text: &str, kind: super::MessageKind, local_id: u64, parent: Option<u64>) -> super::TeamsMessage {
+    match conversation {
+        super::Conversation::Channel(channel) => super::TeamsMessage::ChannelMessage(super::ChannelMessage{
+            channel: channel.clone(),
+            user: username.to_string(),
+            message: text.to_string(),
+            kind,
+            local_id,
+            parent,
+            ..Default::default()
+        }),
+        super::Conversation::Direct(user) => super::TeamsMessage::Message(super::Message{
+            user: user.clone(),
+            message: text.to_string(),
+            kind,
+            local_id,
+            parent,
+            ..Default::default()
+        }),
+    }
+}
+
+/// Replaces the text of an encrypted line by what was encrypted, or by why that failed.
+/// Returns the public key of the peer the message was encrypted with.
+fn decrypt_line(keys: Option<&e2e::KeyPair>, username: &str, line: &mut ChatLine) -> Option<String> {
+    if line.content != super::MessageKind::Encrypted {
+        return None;
+    }
+    line.encrypted = true;
+    let result = match keys {
+        Some(keys) => keys.decrypt(&line.message, line.user == username),
+        None => Err("You have no key to decrypt with".to_string()),
+    };
+    match result {
+        Ok((kind, message, peer_key)) => {
+            line.content = kind;
+            line.message = message;
+            Some(peer_key)
+        },
+        Err(reason) => {
+            line.content = super::MessageKind::Text;
+            line.message = format!("[Encrypted message: {}]", reason);
+            None
+        },
+    }
+}
+
+/// Ids the client chooses itself, for transfers and for matching acknowledgements.
+/// They have to be unique on the server.
+fn new_local_id() -> u64 {
+    static COUNTER: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    let count = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    // Never 0, that means the client chose no id
+    (nanos ^ (u64::from(std::process::id()) << 32) ^ count).max(1)
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn conversation_title(conversation: &super::Conversation) -> String {
+    match conversation {
+        super::Conversation::Direct(user) => user.clone(),
+        super::Conversation::Channel(channel) => format!("#{}", channel),
+    }
+}
+
+/// Time of day for today's messages, with the date for older ones
+fn format_timestamp(timestamp: u64) -> String {
+    use chrono::TimeZone;
+    match chrono::Local.timestamp_opt(timestamp as i64, 0).single() {
+        Some(time) if time.date_naive() == chrono::Local::now().date_naive() => time.format("%H:%M").to_string(),
+        Some(time) => time.format("%Y-%m-%d %H:%M").to_string(),
+        None => "--:--".to_string(),
+    }
+}
+
+/// Splits the text into lines of at most `width` columns, preferring to break at spaces
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = vec![];
+    for paragraph in text.split('\n') {
+        let mut line = String::new();
+        let mut line_width = 0;
+        for word in paragraph.split_inclusive(' ') {
+            let word_width = unicode_width::UnicodeWidthStr::width(word);
+            if line_width + word_width > width && line_width > 0 {
+                lines.push(std::mem::take(&mut line));
+                line_width = 0;
+            }
+            if word_width <= width {
+                line.push_str(word);
+                line_width += word_width;
+                continue;
+            }
+            // Longer than a whole line, so break it anywhere
+            for c in word.chars() {
+                let char_width = unicode_width::UnicodeWidthChar::width(c).unwrap_or(0);
+                if line_width + char_width > width {
+                    lines.push(std::mem::take(&mut line));
+                    line_width = 0;
+                }
+                line.push(c);
+                line_width += char_width;
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Renders the lines of a conversation as "time user: message", continuation lines are indented
+fn render_line(line: &ChatLine, username: &str, width: usize) -> Vec<tui::text::Spans<'static>> {
+    let mut rendered = vec![];
+    let time = format_timestamp(line.timestamp);
+    let time_span = tui::text::Span::styled(format!("{} ", time), tui::style::Style::default().fg(tui::style::Color::DarkGray));
+
+    if line.kind == LineKind::Notice {
+        let style = tui::style::Style::default().fg(tui::style::Color::DarkGray).add_modifier(tui::style::Modifier::ITALIC);
+        let indent = unicode_width::UnicodeWidthStr::width(time.as_str()) + 1;
+        for (index, text) in wrap(&line.message, width.saturating_sub(indent + 2).max(1)).into_iter().enumerate() {
+            let prefix = if index == 0 { time_span.clone() } else { tui::text::Span::raw(" ".repeat(indent)) };
+            rendered.push(tui::text::Spans::from(vec![prefix, tui::text::Span::styled(format!("* {}", text), style)]));
+        }
+        return rendered;
+    }
+
+    let segments = match line.content {
+        super::MessageKind::Image => vec![],
+        _ => code::split(&line.message),
+    };
+    let has_code = segments.iter().any(|segment| matches!(segment, code::Segment::Code { .. }));
+    let edited = if line.edited { " (edited)" } else { "" };
+    let prefix_width = unicode_width::UnicodeWidthStr::width(time.as_str()) + 1
+        + unicode_width::UnicodeWidthStr::width(line.user.as_str()) + edited.len() + 2;
+    // Do not waste half of a small pane on the indentation, start the message on its own line instead.
+    // Code and images always start on their own line, so the indentation stays intact.
+    let own_line = prefix_width * 2 > width || has_code || line.content == super::MessageKind::Image;
+    let indent = if own_line { 2 } else { prefix_width };
+    let body_width = width.saturating_sub(indent).max(1);
+    let user_color = if line.user == username { tui::style::Color::Yellow } else { tui::style::Color::Cyan };
+
+    let mut body: Vec<tui::text::Spans<'static>> = vec![];
+    if line.content == super::MessageKind::Image {
+        // Wrapping would destroy the picture, so cut it at the edge of the pane instead
+        let style = tui::style::Style::default().fg(tui::style::Color::Gray);
+        body.extend(line.message.lines()
+            .map(|row| tui::text::Spans::from(tui::text::Span::styled(row.chars().take(body_width).collect::<String>(), style))));
+    }
+    for segment in segments {
+        match segment {
+            code::Segment::Text(text) => body.extend(markdown::render(text, body_width)),
+            code::Segment::Code { language, code } => body.extend(code::highlight(language, code, body_width)),
+        }
+    }
+    let mut body = body.into_iter();
+
+    let mut header = vec![
+        time_span,
+        tui::text::Span::styled(line.user.clone(), tui::style::Style::default().fg(user_color).add_modifier(tui::style::Modifier::BOLD)),
+        tui::text::Span::styled(edited, tui::style::Style::default().fg(tui::style::Color::DarkGray)),
+        tui::text::Span::raw(": "),
+    ];
+    if !own_line {
+        header.extend(body.next().map(|first| first.0).unwrap_or_default());
+    }
+    rendered.push(tui::text::Spans::from(header));
+    for row in body {
+        let mut spans = vec![tui::text::Span::raw(" ".repeat(indent))];
+        spans.extend(row.0);
+        rendered.push(tui::text::Spans::from(spans));
+    }
+    rendered
+}
+
+/// The rows of a message followed by its markers: reactions, replies, whether it was sent and who read it
+fn decorated_rows(line: &mut ChatLine, username: &str, width: usize, read_by: &std::collections::HashMap<u64, Vec<String>>, direct: bool, selected: bool) -> Vec<tui::text::Spans<'static>> {
+    let marker_style = tui::style::Style::default().fg(tui::style::Color::DarkGray);
+    let mut rows = line.rows(username, width).to_vec();
+    if selected {
+        for span in rows.iter_mut().flat_map(|row| row.0.iter_mut()) {
+            span.style = span.style.bg(tui::style::Color::DarkGray);
+        }
+    }
+    if !line.reactions.is_empty() {
+        let mut spans = vec![tui::text::Span::raw("  ")];
+        for reaction in &line.reactions {
+            let style = match reaction.users.iter().any(|user| user == username) {
+                true => tui::style::Style::default().fg(tui::style::Color::Yellow),
+                false => marker_style,
+            };
+            spans.push(tui::text::Span::styled(format!("{} {}", reaction.emoji, reaction.users.len()), style));
+            spans.push(tui::text::Span::raw("  "));
+        }
+        rows.push(tui::text::Spans::from(spans));
+    }
+    match line.replies {
+        0 => {},
+        1 => rows.push(tui::text::Spans::from(tui::text::Span::styled("  ↳ 1 reply", tui::style::Style::default().fg(tui::style::Color::Cyan)))),
+        replies => rows.push(tui::text::Spans::from(tui::text::Span::styled(format!("  ↳ {} replies", replies), tui::style::Style::default().fg(tui::style::Color::Cyan)))),
+    }
+    match &line.sending {
+        Some((_, SendState::Pending)) => rows.push(tui::text::Spans::from(tui::text::Span::styled("  ⋯ sending", marker_style))),
+        Some((_, SendState::Failed(reason))) => rows.push(tui::text::Spans::from(tui::text::Span::styled(
+            format!("  ✗ not sent: {}", reason), tui::style::Style::default().fg(tui::style::Color::Red)))),
+        _ => {},
+    }
+    let readers = line.id.filter(|_| line.user == username).and_then(|id| read_by.get(&id));
+    if let Some(readers) = readers {
+        let marker = match direct {
+            true => "  ✓ read".to_string(),
+            false => format!("  ✓ read by {}", readers.join(", ")),
+        };
+        rows.push(tui::text::Spans::from(tui::text::Span::styled(marker, marker_style)));
+    }
+    rows
+}
+
+/// Asks the server for everything the client shows right after entering, and publishes the key for encrypted messages
+fn request_overview(stream: &mut super::Stream, public_key: Option<&str>) -> Result<(), std::io::Error> {
+    if let Some(public_key) = public_key {
+        super::send(&super::TeamsMessage::PublishKey(public_key.to_string()), stream)?;
+    }
+    super::send(&super::TeamsMessage::ListChannels, stream)?;
+    super::send(&super::TeamsMessage::ListUsers, stream)
+}
+
+/// Every user has an own key file, in case several share a computer
+fn key_path(directory: &std::path::Path, username: &str) -> std::path::PathBuf {
+    directory.join(format!("{}.key", username))
+}
+
+/// Keys of other users the own user trusts, next to the own key
+fn known_keys_path(directory: &std::path::Path, username: &str) -> std::path::PathBuf {
+    directory.join(format!("{}.known", username))
+}
+
+fn draw(frame: &mut tui::terminal::Frame<tui::backend::CrosstermBackend<std::io::Stdout>>, state: &mut State) {
+    let screen_chunks = tui::layout::Layout::default()
+        .direction(tui::layout::Direction::Vertical)
         .constraints([
-            tui::layout::Constraint::Ratio(1, 3),
-            tui::layout::Constraint::Ratio(2, 3),
+            tui::layout::Constraint::Min(0),
+            tui::layout::Constraint::Length(1),
         ])
         .split(frame.size())
     ;
 
+    // The thread of the selected message gets a pane on the right
+    let thread_open = state.selected_message.is_some();
+    let top_constraints = match thread_open {
+        true => vec![
+            tui::layout::Constraint::Ratio(1, 4),
+            tui::layout::Constraint::Ratio(3, 8),
+            tui::layout::Constraint::Ratio(3, 8),
+        ],
+        false => vec![
+            tui::layout::Constraint::Ratio(1, 3),
+            tui::layout::Constraint::Ratio(2, 3),
+        ],
+    };
+    let top_chunks = tui::layout::Layout::default()
+        .margin(1)
+        .direction(tui::layout::Direction::Horizontal)
+        .constraints(top_constraints)
+        .split(screen_chunks[0])
+    ;
+
+    let input_lines = state.editor.line_count().min(MAX_INPUT_LINES);
     let chat_chunks = tui::layout::Layout::default()
         .direction(tui::layout::Direction::Vertical)
         .constraints([
-            tui::layout::Constraint::Percentage(90),
-            tui::layout::Constraint::Percentage(10),
+            tui::layout::Constraint::Min(0),
+            tui::layout::Constraint::Length(input_lines as u16 + 2),
         ])
         .split(top_chunks[1])
     ;
 
+    let overview_chunks = tui::layout::Layout::default()
+        .direction(tui::layout::Direction::Vertical)
+        .constraints([
+            tui::layout::Constraint::Percentage(50),
+            tui::layout::Constraint::Percentage(50),
+        ])
+        .split(top_chunks[0])
+    ;
+
+    let conversations = state.conversations();
+    let (connection, connection_color) = match state.connection {
+        ConnectionStatus::Connected => ("connected".to_string(), tui::style::Color::Green),
+        ConnectionStatus::Silent(duration) => (format!("no answer for {}s", duration.as_secs()), tui::style::Color::Yellow),
+        ConnectionStatus::Reconnecting => ("reconnecting".to_string(), tui::style::Color::Red),
+    };
     let chats_collection_block = tui::widgets::Block::default()
-        .title("Chats")
+        .title(tui::text::Spans::from(vec![
+            tui::text::Span::raw("Chats "),
+            tui::text::Span::styled(format!("({})", connection), tui::style::Style::default().fg(connection_color)),
+        ]))
         .borders(tui::widgets::Borders::ALL);
-    let chat_list = tui::widgets::List::new([
-        tui::widgets::ListItem::new("Item 1"),
-        tui::widgets::ListItem::new("Item 2"),
-    ])
-        .block(chats_collection_block);
+    let chat_items: Vec<tui::widgets::ListItem> = conversations.iter()
+        .map(|conversation| {
+            let title = match conversation {
+                super::Conversation::Channel(name) => {
+                    let members = state.channels.iter().find(|c| &c.name == name).map(|c| c.members).unwrap_or(0);
+                    format!("#{} ({})", name, members)
+                },
+                super::Conversation::Direct(user) => user.clone(),
+            };
+            tui::widgets::ListItem::new(title)
+        })
+        .collect();
+    let chat_list = tui::widgets::List::new(chat_items)
+        .block(chats_collection_block)
+        .highlight_style(tui::style::Style::default().add_modifier(tui::style::Modifier::REVERSED));
     let mut chat_list_state = tui::widgets::ListState::default();
-    chat_list_state.select(Some(0));
-    frame.render_stateful_widget(chat_list, top_chunks[0], &mut chat_list_state);
+    chat_list_state.select(state.selected.as_ref().and_then(|selected| conversations.iter().position(|c| c == selected)));
+    frame.render_stateful_widget(chat_list, overview_chunks[0], &mut chat_list_state);
+
+    let users_block = tui::widgets::Block::default()
+        .title(format!("Online ({})", state.users.len()))
+        .borders(tui::widgets::Borders::ALL);
+    let user_items: Vec<tui::widgets::ListItem> = state.users.iter()
+        .map(|user| tui::widgets::ListItem::new(user.as_str()))
+        .collect();
+    let user_list = tui::widgets::List::new(user_items)
+        .block(users_block);
+    frame.render_widget(user_list, overview_chunks[1]);
 
+    let inner_width = chat_chunks[0].width.saturating_sub(2) as usize;
+    let inner_height = chat_chunks[0].height.saturating_sub(2) as usize;
+    let username = state.username.clone();
+    let direct = matches!(state.selected, Some(super::Conversation::Direct(_)));
+    let marker_style = tui::style::Style::default().fg(tui::style::Color::DarkGray);
+    let selected_message = state.selected_message;
+    // Rows of the selected message, to scroll it into view
+    let mut selected_rows = 0..0;
+    let rendered: Vec<tui::text::Spans> = match state.selected.as_ref().and_then(|selected| state.buffers.get_mut(selected)) {
+        Some(buffer) => {
+            let read_by = &buffer.read_by;
+            let mut rendered: Vec<tui::text::Spans> = vec![];
+            for line in buffer.lines.iter_mut() {
+                let selected = line.id.is_some() && line.id == selected_message;
+                let rows = decorated_rows(line, &username, inner_width, read_by, direct, selected);
+                if selected {
+                    selected_rows = rendered.len()..rendered.len() + rows.len();
+                }
+                rendered.extend(rows);
+            }
+
+            buffer.typing.retain(|(_, since)| since.elapsed() < TYPING_EXPIRY);
+            let typing: Vec<&str> = buffer.typing.iter().map(|(user, _)| user.as_str()).collect();
+            let typing = match typing.as_slice() {
+                [] => None,
+                [user] => Some(format!("{} is typing…", user)),
+                [first, second] => Some(format!("{} and {} are typing…", first, second)),
+                _ => Some("Several people are typing…".to_string()),
+            };
+            if let Some(typing) = typing {
+                rendered.push(tui::text::Spans::from(tui::text::Span::styled(typing, marker_style.add_modifier(tui::style::Modifier::ITALIC))));
+            }
+            rendered
+        },
+        None => vec![tui::text::Spans::from("Tab switches between chats, /msg <user> <text> starts a new one, /help lists all commands")],
+    };
+    let scroll_max = rendered.len().saturating_sub(inner_height);
+    if std::mem::take(&mut state.reveal_selected) && !selected_rows.is_empty() {
+        // Scroll as little as possible, showing the top of messages that are higher than the pane
+        let shows_top = rendered.len().saturating_sub(selected_rows.start + inner_height);
+        let shows_bottom = rendered.len() - selected_rows.end;
+        state.scroll = state.scroll.clamp(shows_top, shows_bottom.max(shows_top));
+    }
+    let scroll = state.scroll.min(scroll_max);
+    let first_line = rendered.len().saturating_sub(inner_height + scroll);
+    let visible: Vec<tui::text::Spans> = rendered.into_iter().skip(first_line).take(inner_height).collect();
+
+    let mut title = state.selected.as_ref().map(conversation_title).unwrap_or_else(|| "Chat Messages".to_string());
+    if state.selected.as_ref().map(|selected| state.is_encrypted(selected)).unwrap_or(false) {
+        title.push_str(" (end-to-end encrypted)");
+    }
+    if scroll > 0 {
+        title.push_str(&format!(" (scrolled up {} lines)", scroll));
+    }
     let chats_collection_block = tui::widgets::Block::default()
-        .title("Chat Messages")
+        .title(title)
         .borders(tui::widgets::Borders::ALL);
-    let messages_paragraph = tui::widgets::Paragraph::new(
-        tui::text::Text::from(
-            tui::text::Spans::from(vec![
-                tui::text::Span::raw("User: Message"),
-            ])
-        )
-    )
+    let messages_paragraph = tui::widgets::Paragraph::new(tui::text::Text::from(visible))
         .block(chats_collection_block);
     frame.render_widget(messages_paragraph, chat_chunks[0]);
 
+    if thread_open {
+        let thread_width = top_chunks[2].width.saturating_sub(2) as usize;
+        let thread_height = top_chunks[2].height.saturating_sub(2) as usize;
+        let mut rows = vec![];
+        if let Some(buffer) = state.selected.as_ref().and_then(|selected| state.buffers.get_mut(selected)) {
+            let read_by = &buffer.read_by;
+            match buffer.lines.iter_mut().find(|line| line.id == selected_message) {
+                Some(root) => rows.extend(decorated_rows(root, &username, thread_width, read_by, direct, false)),
+                None => rows.push(tui::text::Spans::from(tui::text::Span::styled("The message was deleted", marker_style))),
+            }
+            let replies = selected_message.and_then(|id| buffer.threads.get_mut(&id));
+            for line in replies.into_iter().flat_map(|thread| thread.lines.iter_mut()) {
+                rows.extend(decorated_rows(line, &username, thread_width, read_by, direct, false));
+            }
+        }
+        // The newest replies stay in view, like in the conversation
+        let first_row = rows.len().saturating_sub(thread_height);
+        let thread_block = tui::widgets::Block::default()
+            .title("Thread (Esc closes it)")
+            .borders(tui::widgets::Borders::ALL);
+        let thread_paragraph = tui::widgets::Paragraph::new(tui::text::Text::from(rows.split_off(first_row)))
+            .block(thread_block);
+        frame.render_widget(thread_paragraph, top_chunks[2]);
+    }
+
+    // Scroll the input so that the cursor is always visible
+    let input_width = chat_chunks[1].width.saturating_sub(2) as usize;
+    let (cursor_line, cursor_column) = state.editor.cursor_position();
+    let horizontal_scroll = (cursor_column + 1).saturating_sub(input_width);
+    let vertical_scroll = (cursor_line + 1).saturating_sub(input_lines);
+    let input_title = match thread_open {
+        true => "Reply in thread (Alt+Enter for a new line)",
+        false => "Message (Alt+Enter for a new line)",
+    };
     let chats_collection_block = tui::widgets::Block::default()
-        .title("Message")
+        .title(input_title)
         .borders(tui::widgets::Borders::ALL);
-    frame.render_widget(chats_collection_block, chat_chunks[1]);
-    frame.set_cursor(chat_chunks[1].x + 1, chat_chunks[1].y + 1);
+    let input_paragraph = tui::widgets::Paragraph::new(state.editor.text())
+        .scroll((vertical_scroll as u16, horizontal_scroll as u16))
+        .block(chats_collection_block);
+    frame.render_widget(input_paragraph, chat_chunks[1]);
+    frame.set_cursor(
+        chat_chunks[1].x + 1 + (cursor_column - horizontal_scroll) as u16,
+        chat_chunks[1].y + 1 + (cursor_line - vertical_scroll) as u16,
+    );
+
+    let status = match state.status.is_empty() {
+        true => tui::widgets::Paragraph::new(state.transfer_status())
+            .style(tui::style::Style::default().fg(tui::style::Color::Green)),
+        false => tui::widgets::Paragraph::new(state.status.as_str())
+            .style(tui::style::Style::default().fg(tui::style::Color::Red)),
+    };
+    frame.render_widget(status, screen_chunks[1]);
+
+    state.scroll_max = scroll_max;
+    state.chat_width = inner_width;
+    state.scroll = scroll;
+}
+
+fn restore_terminal() {
+    if let Err(e) = crossterm::terminal::disable_raw_mode() {
+        log::error!("Could not disable raw mode {:?}", e);
+    }
+    std::io::stdout()
+        .queue(crossterm::event::PopKeyboardEnhancementFlags).unwrap()
+        .queue(crossterm::event::DisableBracketedPaste).unwrap()
+        .queue(ResetColor).unwrap()
+        .queue(cursor::Show).unwrap()
+        .queue(LeaveAlternateScreen).unwrap()
+        .flush().unwrap()
+    ;
 }
 
-pub fn run() -> Result<(), std::io::Error> {
+/// Sends the accepted file chunk by chunk, stops early if the upload was cancelled in the meantime
+fn upload(id: u64, connection: std::sync::Arc<std::sync::Mutex<super::Connection>>, state: std::sync::Arc<std::sync::Mutex<State>>) {
+    let (path, size) = match state.lock().unwrap().uploads.get(&id) {
+        Some(upload) => (upload.path.clone(), upload.size),
+        None => return,
+    };
+    let failed = |reason: String| {
+        let mut locked_state = state.lock().unwrap();
+        if let Some(upload) = locked_state.uploads.remove(&id) {
+            locked_state.notice_in(super::Conversation::Direct(upload.user), &format!("Sending {} failed: {}", upload.name, reason));
+        }
+        super::TeamsMessage::FileCancel { id, reason }
+    };
+
+    let mut file = match std::fs::File::open(&path) {
+        Ok(file) => file,
+        Err(e) => {
+            let cancel = failed(format!("Could not open the file: {}", e));
+            let _ = super::send(&cancel, &mut connection.lock().unwrap().stream);
+            return;
+        },
+    };
+    let mut buffer = vec![0; transfer::CHUNK_SIZE];
+    let mut offset = 0;
+    loop {
+        let n = match std::io::Read::read(&mut file, &mut buffer) {
+            Ok(n) => n,
+            Err(e) => {
+                let cancel = failed(format!("Could not read the file: {}", e));
+                let _ = super::send(&cancel, &mut connection.lock().unwrap().stream);
+                return;
+            },
+        };
+        if n == 0 {
+            break;
+        }
+        if offset + n as u64 > size {
+            let cancel = failed("The file changed while sending it".to_string());
+            let _ = super::send(&cancel, &mut connection.lock().unwrap().stream);
+            return;
+        }
+        if !state.lock().unwrap().uploads.contains_key(&id) {
+            log::info!("Upload {} was cancelled", id);
+            return;
+        }
+
+        let chunk = super::TeamsMessage::FileChunk(super::FileChunk { id, offset, data: buffer[..n].to_vec() });
+        if let Err(e) = super::send(&chunk, &mut connection.lock().unwrap().stream) {
+            // The read thread notices the broken connection and reconnects
+            log::error!("Could not send chunk of upload {} {:?}", id, e);
+            return;
+        }
+        offset += n as u64;
+        if let Some(upload) = state.lock().unwrap().uploads.get_mut(&id) {
+            upload.sent = offset;
+        }
+    }
+
+    if offset != size {
+        let cancel = failed("The file changed while sending it".to_string());
+        let _ = super::send(&cancel, &mut connection.lock().unwrap().stream);
+        return;
+    }
+    let mut locked_state = state.lock().unwrap();
+    if let Some(upload) = locked_state.uploads.remove(&id) {
+        locked_state.notice_in(super::Conversation::Direct(upload.user), &format!("Sent {}", upload.name));
+    }
+}
+
+pub fn run(config: crate::config::ClientConfig) -> Result<(), std::io::Error> {
     ctrlc::set_handler(|| {
-        std::io::stdout()
-            .queue(ResetColor).unwrap()
-            .queue(cursor::Show).unwrap()
-            .queue(LeaveAlternateScreen).unwrap()
-            .flush().unwrap()
-        ;
+        restore_terminal();
         std::process::exit(0);
     }).expect("Could not register the ctrl-c handler!");
+    let connector = super::transport::Connector::new(config.ca.as_deref(), config.server_name.clone())?;
 
     let font = Font::from_basic(BasicFonts::Big).unwrap();
     let prntr = Printer::with_font(font);
@@ -121,9 +1370,22 @@ pub fn run() -> Result<(), std::io::Error> {
     ;
 
     let mut terminal = tui::Terminal::new(tui::backend::CrosstermBackend::new(std::io::stdout()))?;
-    let mut connection = std::net::TcpStream::connect("127.0.0.1:7474").expect("Could not establish connection!");
-
-    let username = setup_username(&mut connection);
+    let stream = match connector.connect(&config.connect) {
+        Ok(stream) => stream,
+        Err(e) => {
+            restore_terminal();
+            log::error!("Could not establish connection to {} {:?}", config.connect, e);
+            return Err(e);
+        },
+    };
+    let (connection, mut username, token) = match setup_account(&connector, &config.connect, super::Connection::new(stream), config.user.clone(), config.register) {
+        Ok(account) => account,
+        Err(e) => {
+            restore_terminal();
+            log::error!("Could not log in to {} {:?}", config.connect, e);
+            return Err(e);
+        },
+    };
     std::io::stdout()
         .queue(Clear(ClearType::All))?
         .queue(cursor::MoveTo(0, 0))?
@@ -131,74 +1393,264 @@ pub fn run() -> Result<(), std::io::Error> {
         .flush()?
     ;
 
-    connection.set_nonblocking(true).unwrap();
+    connection.stream.set_nonblocking(true).unwrap();
     let connection = std::sync::Arc::new(std::sync::Mutex::new(connection));
 
+    // Without a key everything works except encryption
+    let keys = match e2e::KeyPair::load_or_create(&key_path(&config.keys, &username)) {
+        Ok(keys) => Some(keys),
+        Err(e) => {
+            log::error!("Could not load the key, encryption is not available {:?}", e);
+            None
+        },
+    };
+    let public_key = keys.as_ref().map(|keys| keys.public_key());
+    let peer_keys = match e2e::KnownKeys::load(&known_keys_path(&config.keys, &username)) {
+        Ok(peer_keys) => peer_keys,
+        Err(e) => {
+            log::error!("Could not load the known keys, keys are only trusted until the end of this session {:?}", e);
+            e2e::KnownKeys::default()
+        },
+    };
+
     let (sx, rx) = std::sync::mpsc::channel::<Command>();
+    let state = std::sync::Arc::new(std::sync::Mutex::new(State {
+        username: username.clone(),
+        keys,
+        peer_keys,
+        ..State::default()
+    }));
+
+    if let Err(e) = request_overview(&mut connection.lock().unwrap().stream, public_key.as_deref()) {
+        log::error!("Could not request the overview {:?}", e);
+    }
 
     let s_read = sx.clone();
     let read_connection_clone = std::sync::Arc::clone(&connection);
+    let read_state = std::sync::Arc::clone(&state);
+    let heartbeat = config.heartbeat;
     std::thread::spawn(move || {
+        let mut last_received = std::time::Instant::now();
+        let mut last_ping = std::time::Instant::now();
         loop {
-            match super::try_recv(&mut read_connection_clone.lock().unwrap()) {
-                Ok(option) => if let Some(message) = option {
-                    s_read.send(Command::NewMessage(message)).unwrap();
-                },
-                Err(_) => s_read.send(Command::Reinit).unwrap(),
+            // The main thread sets up the new connection, until then there is nothing to read
+            if read_state.lock().unwrap().connection == ConnectionStatus::Reconnecting {
+                last_received = std::time::Instant::now();
+                std::thread::sleep(std::time::Duration::from_secs(1));
+                continue;
             }
+
+            // Drain everything that arrived, e.g. the offline queue right after entering
+            let mut lost = false;
+            loop {
+                match super::try_recv(&mut read_connection_clone.lock().unwrap()) {
+                    Ok(Some(message)) => {
+                        last_received = std::time::Instant::now();
+                        s_read.send(Command::NewMessage(message)).unwrap();
+                    },
+                    Ok(None) => break,
+                    Err(_) => {
+                        lost = true;
+                        break;
+                    },
+                }
+            }
+
+            // Any message shows that the server is there, a ping is only needed when it is quiet
+            let silent = last_received.elapsed();
+            if !lost && silent >= heartbeat.timeout {
+                log::warn!("The server did not answer for {:?}, reconnect", silent);
+                lost = true;
+            }
+            if lost {
+                if read_state.lock().unwrap().connection_lost() {
+                    s_read.send(Command::Reinit).unwrap();
+                }
+                continue;
+            }
+            if silent >= heartbeat.interval && last_ping.elapsed() >= heartbeat.interval {
+                last_ping = std::time::Instant::now();
+                s_read.send(Command::Send(super::TeamsMessage::Ping)).unwrap();
+            }
+            let mut locked_state = read_state.lock().unwrap();
+            if locked_state.connection != ConnectionStatus::Reconnecting {
+                locked_state.connection = match silent >= heartbeat.interval + PONG_GRACE {
+                    true => ConnectionStatus::Silent(silent),
+                    false => ConnectionStatus::Connected,
+                };
+            }
+            drop(locked_state);
             std::thread::sleep(std::time::Duration::from_secs(1));
         }
     });
 
+    // Without raw mode the terminal would echo and buffer the input until enter
+    crossterm::terminal::enable_raw_mode()?;
+    std::io::stdout()
+        .queue(crossterm::event::EnableBracketedPaste)?
+        .queue(crossterm::event::PushKeyboardEnhancementFlags(crossterm::event::KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES))?
+        .flush()?
+    ;
+
     let s_new_message = sx.clone();
+    let input_state = std::sync::Arc::clone(&state);
     std::thread::spawn(move || {
-        let mut cursor_pos = 0;
         loop {
-            let mut message = String::new();
-            loop {
-                match crossterm::event::read() {
-                    Ok(event) => match event {
-                        crossterm::event::Event::Key(crossterm::event::KeyEvent { code, .. }) => {
-                            match code {
-                                // TODO: Implement other features!
-                                KeyCode::Enter => {
-                                    break;
-                                },
-                                KeyCode::Char(c) => {
-                                    message.push(c);
-                                    cursor_pos += 1;
-                                },
-                                KeyCode::Backspace => {
-                                    message.remove(cursor_pos - 1);
-                                    cursor_pos -= 1;
-                                },
-                                _ => {},
-                            }
+            // Wake up regularly, so typing stops after a while without key presses
+            let event = match crossterm::event::poll(std::time::Duration::from_millis(500)) {
+                Ok(true) => crossterm::event::read(),
+                Ok(false) => {
+                    for request in input_state.lock().unwrap().typing_update(false) {
+                        s_new_message.send(Command::Send(request)).unwrap();
+                    }
+                    continue;
+                },
+                Err(e) => Err(e),
+            };
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => {
+                    log::error!("Event error {:?}", e);
+                    continue;
+                }
+            };
+
+            let mut locked_state = input_state.lock().unwrap();
+            let text_before = locked_state.editor.text().to_string();
+            let editor = &mut locked_state.editor;
+            let command = match event {
+                crossterm::event::Event::Key(crossterm::event::KeyEvent { code, modifiers, kind, .. }) if kind != crossterm::event::KeyEventKind::Release => {
+                    let control = modifiers.contains(crossterm::event::KeyModifiers::CONTROL);
+                    let alt = modifiers.contains(crossterm::event::KeyModifiers::ALT);
+                    let shift = modifiers.contains(crossterm::event::KeyModifiers::SHIFT);
+                    match code {
+                        KeyCode::Char('c') if control => Some(Command::Quit),
+                        // Most terminals only report Shift+Enter with the keyboard enhancement, Alt+Enter always works
+                        KeyCode::Enter if shift || alt => {
+                            editor.insert_char('\n');
+                            None
                         },
-                        crossterm::event::Event::Paste(string) => {
-                            message.push_str(&string);
+                        KeyCode::Enter => {
+                            let message = editor.submit();
+                            Some(Command::Input(message.trim_end().to_string()))
                         },
-                        crossterm::event::Event::Resize(_, _new_height) => {
-                            // TODO: Call resize on tui!
+                        KeyCode::Char('w') if control => {
+                            editor.delete_word_backward();
+                            None
                         },
-                        _ => {},
-                    },
-                    Err(e) => {
-                        log::error!("Event error {:?}", e);
-                        continue;
+                        KeyCode::Char('a') if control => {
+                            editor.move_home();
+                            None
+                        },
+                        KeyCode::Char('e') if control => {
+                            editor.move_end();
+                            None
+                        },
+                        KeyCode::Char('b') if alt => {
+                            editor.move_word_left();
+                            None
+                        },
+                        KeyCode::Char('f') if alt => {
+                            editor.move_word_right();
+                            None
+                        },
+                        KeyCode::Char('d') if alt => {
+                            editor.delete_word_forward();
+                            None
+                        },
+                        KeyCode::Char(digit @ '1'..='5') if alt => {
+                            let index = digit as usize - '1' as usize;
+                            Some(Command::React(QUICK_REACTIONS[index].to_string()))
+                        },
+                        KeyCode::Char(c) if !control => {
+                            editor.insert_char(c);
+                            None
+                        },
+                        KeyCode::Backspace if control || alt => {
+                            editor.delete_word_backward();
+                            None
+                        },
+                        KeyCode::Backspace => {
+                            editor.backspace();
+                            None
+                        },
+                        KeyCode::Delete if control || alt => {
+                            editor.delete_word_forward();
+                            None
+                        },
+                        KeyCode::Delete => {
+                            editor.delete();
+                            None
+                        },
+                        KeyCode::Left if control || alt => {
+                            editor.move_word_left();
+                            None
+                        },
+                        KeyCode::Left => {
+                            editor.move_left();
+                            None
+                        },
+                        KeyCode::Right if control || alt => {
+                            editor.move_word_right();
+                            None
+                        },
+                        KeyCode::Right => {
+                            editor.move_right();
+                            None
+                        },
+                        KeyCode::Home => {
+                            editor.move_home();
+                            None
+                        },
+                        KeyCode::End => {
+                            editor.move_end();
+                            None
+                        },
+                        KeyCode::Up if alt => Some(Command::SelectMessage(-1)),
+                        KeyCode::Down if alt => Some(Command::SelectMessage(1)),
+                        KeyCode::Esc => Some(Command::SelectMessage(0)),
+                        KeyCode::Up => {
+                            editor.up();
+                            None
+                        },
+                        KeyCode::Down => {
+                            editor.down();
+                            None
+                        },
+                        KeyCode::Tab => Some(Command::Select(1)),
+                        KeyCode::BackTab => Some(Command::Select(-1)),
+                        KeyCode::PageUp => Some(Command::Scroll(SCROLL_STEP as isize)),
+                        KeyCode::PageDown => Some(Command::Scroll(-(SCROLL_STEP as isize))),
+                        _ => None,
                     }
-                }
-            }
+                },
+                crossterm::event::Event::Paste(string) => {
+                    editor.insert_str(&string);
+                    None
+                },
+                // The render thread picks up the new size on its next draw
+                _ => None,
+            };
+            let key_pressed = locked_state.editor.text() != text_before;
+            let typing_requests = locked_state.typing_update(key_pressed);
+            drop(locked_state);
 
-            s_new_message.send(Command::Input(message.trim_end().to_string())).unwrap();
-            message.clear();
+            if let Some(command) = command {
+                s_new_message.send(command).unwrap();
+            }
+            for request in typing_requests {
+                s_new_message.send(Command::Send(request)).unwrap();
+            }
         }
     });
 
+    // Get rid of the greeting, tui only redraws what changed
+    terminal.clear()?;
+    let draw_state = std::sync::Arc::clone(&state);
     std::thread::spawn(move || {
         loop {
             match terminal.draw(|frame| {
-                draw(frame);
+                draw(frame, &mut draw_state.lock().unwrap());
             }) {
                 Ok(_) => {},
                 Err(e) => log::error!("Render failed!: {:?}", e),
@@ -209,78 +1661,569 @@ pub fn run() -> Result<(), std::io::Error> {
     });
 
     loop {
-        match rx.recv().unwrap() {
+        // Some commands lead to a request to the server, like loading the history of a conversation
+        let request = match rx.recv().unwrap() {
             Command::NewMessage(teams_message) => match teams_message {
                 super::TeamsMessage::Message(m) => {
-                    let _output = format_args!("New message!\n'{}': '{}'\n\n", m.user, m.message);
+                    let mut locked_state = state.lock().unwrap();
+                    let conversation = super::Conversation::Direct(m.user.clone());
+                    let mut line = ChatLine::new(LineKind::Message, m.kind, Some(m.id), m.user.clone(), m.message, m.timestamp);
+                    // Answer encrypted as well
+                    if let Some(peer_key) = decrypt_line(locked_state.keys.as_ref(), &username, &mut line) {
+                        locked_state.learn_key(&m.user, peer_key, false);
+                    }
+                    locked_state.receive(conversation, m.parent, line)
                 },
-                _ => {},
-            },
-            Command::Input(i) => {
-                if i == "exit" {
-                    if let Some(err) = super::send(& super::TeamsMessage::UserExit(username.to_string()), &mut connection.lock().unwrap()).err() {
-                        log::error!("Could not unregister client! {:?}", err);
+                super::TeamsMessage::ChannelMessage(m) => {
+                    let conversation = super::Conversation::Channel(m.channel.clone());
+                    let line = ChatLine::new(LineKind::Message, m.kind, Some(m.id), m.user, m.message, m.timestamp);
+                    state.lock().unwrap().receive(conversation, m.parent, line)
+                },
+                super::TeamsMessage::EditMessage(id, text) => {
+                    state.lock().unwrap().change_message(id, Some(text));
+                    None
+                },
+                super::TeamsMessage::DeleteMessage(id) => {
+                    state.lock().unwrap().change_message(id, None);
+                    None
+                },
+                super::TeamsMessage::Reactions { id, reactions } => {
+                    state.lock().unwrap().set_reactions(id, reactions);
+                    None
+                },
+                super::TeamsMessage::Typing { user, conversation } => {
+                    let mut locked_state = state.lock().unwrap();
+                    let typing = &mut locked_state.buffers.entry(conversation).or_default().typing;
+                    typing.retain(|(typing_user, _)| *typing_user != user);
+                    typing.push((user, std::time::Instant::now()));
+                    None
+                },
+                super::TeamsMessage::StoppedTyping { user, conversation } => {
+                    if let Some(buffer) = state.lock().unwrap().buffers.get_mut(&conversation) {
+                        buffer.typing.retain(|(typing_user, _)| *typing_user != user);
+                    }
+                    None
+                },
+                super::TeamsMessage::Read { user, conversation, id } => {
+                    let mut locked_state = state.lock().unwrap();
+                    let readers = locked_state.buffers.entry(conversation).or_default().read_by.entry(id).or_default();
+                    if !readers.contains(&user) {
+                        readers.push(user);
+                    }
+                    None
+                },
+                super::TeamsMessage::Ack { local_id, result } => {
+                    let mut locked_state = state.lock().unwrap();
+                    if let Some(reason) = locked_state.acknowledge(local_id, result) {
+                        locked_state.status = format!("!! {} !!", reason);
+                    }
+                    None
+                },
+                super::TeamsMessage::NewUser(user) => {
+                    let mut locked_state = state.lock().unwrap();
+                    if let Err(index) = locked_state.users.binary_search(&user) {
+                        locked_state.users.insert(index, user);
+                    }
+                    None
+                },
+                super::TeamsMessage::UserExit(user) => {
+                    state.lock().unwrap().users.retain(|u| *u != user);
+                    None
+                },
+                super::TeamsMessage::UserList(users) => {
+                    let mut locked_state = state.lock().unwrap();
+                    if std::mem::take(&mut locked_state.show_user_list) {
+                        locked_state.notice(&format!("Online: {}", users.join(", ")));
+                    }
+                    locked_state.users = users;
+                    None
+                },
+                super::TeamsMessage::UserRenamed { old, new } => {
+                    let mut locked_state = state.lock().unwrap();
+                    locked_state.rename(&old, &new);
+                    if old == username {
+                        // The key belongs to the user, not to the name
+                        if let Err(e) = std::fs::rename(key_path(&config.keys, &old), key_path(&config.keys, &new)) {
+                            log::error!("Could not rename the key file {:?}", e);
+                        }
+                        locked_state.peer_keys.move_to(&known_keys_path(&config.keys, &new));
+                        username = new.clone();
+                        locked_state.username = new.clone();
+                        locked_state.notice(&format!("You are now known as {}", new));
+                    }
+                    None
+                },
+                super::TeamsMessage::UserOffline(user) => {
+                    state.lock().unwrap().status = format!("!! {} is offline, the message is delivered when they are back !!", user);
+                    None
+                },
+                super::TeamsMessage::ChannelList(channels) => {
+                    let mut locked_state = state.lock().unwrap();
+                    if std::mem::take(&mut locked_state.show_channel_list) {
+                        let list: Vec<String> = channels.iter()
+                            .map(|c| format!("#{} ({}{})", c.name, c.members, if c.joined { ", joined" } else { "" }))
+                            .collect();
+                        let text = if list.is_empty() { "There are no channels yet, /create one".to_string() } else { format!("Channels: {}", list.join(", ")) };
+                        locked_state.notice(&text);
+                    }
+                    locked_state.channels = channels;
+                    let selected_left = match &locked_state.selected {
+                        Some(super::Conversation::Channel(name)) => !locked_state.channels.iter().any(|c| &c.name == name && c.joined),
+                        _ => false,
+                    };
+                    if selected_left {
+                        locked_state.selected = None;
+                    }
+                    if locked_state.selected.is_none() {
+                        locked_state.select_relative(1)
+                    } else {
+                        None
+                    }
+                },
+                super::TeamsMessage::History { conversation, entries, thread } => {
+                    let locked_state = &mut *state.lock().unwrap();
+                    let keys = locked_state.keys.as_ref();
+                    let buffer = locked_state.buffers.entry(conversation.clone()).or_default();
+                    let peer_key = match thread {
+                        Some(id) => buffer.threads.entry(id).or_default().merge_history(entries, keys, &username),
+                        None => buffer.merge_history(entries, keys, &username),
+                    };
+                    // Stay encrypted after a restart, the reply tells whether the peer has another key now
+                    match (conversation, peer_key) {
+                        (super::Conversation::Direct(user), Some(key)) if !locked_state.encrypted.contains(&user) => {
+                            if locked_state.peer_keys.get(&user).is_none() {
+                                locked_state.learn_key(&user, key, true);
+                            }
+                            Some(super::TeamsMessage::KeyRequest(user))
+                        },
+                        _ => None,
+                    }
+                },
+                super::TeamsMessage::PublicKey { user, key } => {
+                    let mut locked_state = state.lock().unwrap();
+                    match key {
+                        Some(key) if e2e::is_valid_key(&key) => locked_state.learn_key(&user, key, false),
+                        _ => {
+                            locked_state.encrypted.remove(&user);
+                            let text = format!("{} has no key yet, messages to them can only be encrypted once they logged in again", user);
+                            locked_state.notice_in(super::Conversation::Direct(user), &text);
+                        },
+                    }
+                    None
+                },
+                super::TeamsMessage::FileOffer(offer) => {
+                    let mut locked_state = state.lock().unwrap();
+                    let number = locked_state.next_transfer_number();
+                    let download = transfer::Download::new(number, offer.user.clone(), &offer.name, offer.size, offer.checksum);
+                    locked_state.notice_in(super::Conversation::Direct(offer.user), &format!(
+                        "Offers the file {} ({}), /accept {} or /decline {}", download.name, transfer::format_size(offer.size), number, number));
+                    locked_state.downloads.insert(offer.id, download);
+                    None
+                },
+                super::TeamsMessage::FileAccept(id) => {
+                    let mut locked_state = state.lock().unwrap();
+                    if let Some(accepted) = locked_state.uploads.get(&id) {
+                        let user = accepted.user.clone();
+                        let text = format!("{} accepted {}", user, accepted.name);
+                        locked_state.notice_in(super::Conversation::Direct(user), &text);
+                        let upload_connection = std::sync::Arc::clone(&connection);
+                        let upload_state = std::sync::Arc::clone(&state);
+                        std::thread::spawn(move || upload(id, upload_connection, upload_state));
+                    }
+                    None
+                },
+                super::TeamsMessage::FileChunk(chunk) => {
+                    let mut locked_state = state.lock().unwrap();
+                    let result = match locked_state.downloads.get_mut(&chunk.id) {
+                        Some(download) => download.write(chunk.offset, &chunk.data),
+                        // Cancelled by us while the chunk was on the way
+                        None => continue,
+                    };
+                    match result {
+                        Ok(false) => None,
+                        Ok(true) => {
+                            let download = locked_state.downloads.remove(&chunk.id).unwrap();
+                            let conversation = super::Conversation::Direct(download.user.clone());
+                            let name = download.name.clone();
+                            match download.finish() {
+                                Ok(path) => locked_state.notice_in(conversation, &format!("Received {}, saved to {}", name, path.display())),
+                                Err(e) => locked_state.notice_in(conversation, &format!("Receiving {} failed: {}", name, e)),
+                            }
+                            None
+                        },
+                        Err(e) => {
+                            let download = locked_state.downloads.remove(&chunk.id).unwrap();
+                            locked_state.notice_in(super::Conversation::Direct(download.user.clone()), &format!("Receiving {} failed: {}", download.name, e));
+                            download.abort();
+                            Some(super::TeamsMessage::FileCancel { id: chunk.id, reason: format!("The recipient could not save the file: {}", e) })
+                        },
                     }
-                    println!("Exiting...");
-                    break;
-                } else {
-                    let parts: Vec<&str> = i.split(":").collect();
-                    if parts.len() != 2 {
-                        std::io::stdout()
-                            .execute(cursor::SavePosition).unwrap()
-                            .execute(cursor::MoveTo(0, crossterm::terminal::size().unwrap().1 - 1)).unwrap()
-                            .execute(Clear(ClearType::UntilNewLine)).unwrap()
-                            .execute(Print("!! User: Message <- that's the format. Please try again !!")).unwrap()
-                            .execute(cursor::RestorePosition).unwrap()
-                        ;
-                        continue;
+                },
+                super::TeamsMessage::FileCancel { id, reason } => {
+                    let mut locked_state = state.lock().unwrap();
+                    if let Some(upload) = locked_state.uploads.remove(&id) {
+                        locked_state.notice_in(super::Conversation::Direct(upload.user), &format!("Sending {} stopped: {}", upload.name, reason));
                     }
-                    std::io::stdout()
-                        .execute(cursor::SavePosition).unwrap()
-                        .execute(cursor::MoveTo(0, crossterm::terminal::size().unwrap().1 - 1)).unwrap()
-                        .execute(Clear(ClearType::UntilNewLine)).unwrap()
-                        .execute(cursor::RestorePosition).unwrap()
-                    ;
-
-                    if super::send(& super::TeamsMessage::Message(
-                        super::Message{
-                            user: parts[0].to_string(),
-                            message: parts[1].to_string(),
-                        },
-                    ), &mut connection.lock().unwrap()).is_ok() {
-                        continue;
+                    if let Some(download) = locked_state.downloads.remove(&id) {
+                        locked_state.notice_in(super::Conversation::Direct(download.user.clone()), &format!("Receiving {} stopped: {}", download.name, reason));
+                        download.abort();
                     }
+                    None
+                },
+                super::TeamsMessage::Error(reason) => {
+                    state.lock().unwrap().status = format!("!! {} !!", reason);
+                    None
+                },
+                super::TeamsMessage::Ping => Some(super::TeamsMessage::Pong),
+                _ => None,
+            },
+            Command::Select(delta) => state.lock().unwrap().select_relative(delta),
+            Command::Send(request) => Some(request),
+            Command::Scroll(delta) => state.lock().unwrap().scroll_by(delta),
+            Command::SelectMessage(delta) => state.lock().unwrap().select_message(delta),
+            Command::React(emoji) => state.lock().unwrap().reaction_request(emoji),
+            Command::Quit => {
+                if let Some(err) = super::send(& super::TeamsMessage::UserExit(username.to_string()), &mut connection.lock().unwrap().stream).err() {
+                    log::error!("Could not unregister client! {:?}", err);
+                }
+                break;
+            },
+            Command::Input(i) => {
+                if i.trim().is_empty() {
+                    continue;
+                }
 
-                    sx.send(Command::Reinit).unwrap();
+                let mut locked_state = state.lock().unwrap();
+                locked_state.status.clear();
+                match commands::parse(&i) {
+                    commands::Input::Text(text) => match locked_state.selected.clone() {
+                        Some(conversation) => {
+                            let parent = locked_state.selected_message;
+                            locked_state.send_text(conversation, parent, text)
+                        },
+                        None => {
+                            locked_state.notice("Select a chat with Tab or start one with /msg <user> <text>");
+                            None
+                        },
+                    },
+                    commands::Input::Msg { target, text } => {
+                        let conversation = match target.strip_prefix('#') {
+                            Some(channel) => super::Conversation::Channel(channel.to_string()),
+                            None => super::Conversation::Direct(target),
+                        };
+                        let request = locked_state.send_text(conversation.clone(), None, text);
+                        // Switch to the conversation of the message
+                        if let Some(history_request) = locked_state.open(conversation) {
+                            if let Err(e) = super::send(&history_request, &mut connection.lock().unwrap().stream) {
+                                log::error!("Could not request the history {:?}", e);
+                            }
+                        }
+                        request
+                    },
+                    commands::Input::Create(channel) => Some(super::TeamsMessage::CreateChannel(channel)),
+                    commands::Input::Join(channel) => Some(super::TeamsMessage::JoinChannel(channel)),
+                    commands::Input::Leave(channel) if !channel.is_empty() => Some(super::TeamsMessage::LeaveChannel(channel)),
+                    commands::Input::Leave(_) => match locked_state.selected.clone() {
+                        Some(super::Conversation::Channel(channel)) => Some(super::TeamsMessage::LeaveChannel(channel)),
+                        _ => {
+                            locked_state.notice("The selected chat is no channel, use /leave <#channel>");
+                            None
+                        },
+                    },
+                    commands::Input::Channels => {
+                        locked_state.show_channel_list = true;
+                        Some(super::TeamsMessage::ListChannels)
+                    },
+                    commands::Input::Who => {
+                        locked_state.show_user_list = true;
+                        Some(super::TeamsMessage::ListUsers)
+                    },
+                    commands::Input::Nick(name) => Some(super::TeamsMessage::ChangeNick(name)),
+                    commands::Input::Send(path) => match locked_state.selected.clone() {
+                        Some(super::Conversation::Direct(user)) => {
+                            let path = std::path::PathBuf::from(path);
+                            let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+                            match transfer::checksum(&path) {
+                                Ok((size, checksum)) => {
+                                    let id = new_local_id();
+                                    let number = locked_state.next_transfer_number();
+                                    locked_state.notice(&format!("Offered {} ({}), waiting for {} to accept, /cancel {} to stop",
+                                        name, transfer::format_size(size), user, number));
+                                    locked_state.uploads.insert(id, transfer::Upload { number, user: user.clone(), path, name: name.clone(), size, sent: 0 });
+                                    Some(super::TeamsMessage::FileOffer(super::FileOffer { id, user, name, size, checksum }))
+                                },
+                                Err(e) => {
+                                    locked_state.notice(&format!("Could not read the file {}: {}", path.display(), e));
+                                    None
+                                },
+                            }
+                        },
+                        _ => {
+                            locked_state.notice("Files can only be sent to a user, select the chat with them first");
+                            None
+                        },
+                    },
+                    commands::Input::Accept(number) => {
+                        let id = locked_state.transfer_id(number);
+                        match id.and_then(|id| locked_state.downloads.get_mut(&id).map(|download| (id, download))) {
+                            Some((id, download)) if !download.is_accepted() => match download.accept(&config.downloads) {
+                                Ok(()) if download.size == 0 => {
+                                    let download = locked_state.downloads.remove(&id).unwrap();
+                                    let conversation = super::Conversation::Direct(download.user.clone());
+                                    let name = download.name.clone();
+                                    match download.finish() {
+                                        Ok(path) => locked_state.notice_in(conversation, &format!("Received {}, saved to {}", name, path.display())),
+                                        Err(e) => locked_state.notice_in(conversation, &format!("Receiving {} failed: {}", name, e)),
+                                    }
+                                    Some(super::TeamsMessage::FileAccept(id))
+                                },
+                                Ok(()) => Some(super::TeamsMessage::FileAccept(id)),
+                                Err(e) => {
+                                    let text = format!("Could not create the file in {}: {}", config.downloads.display(), e);
+                                    locked_state.notice(&text);
+                                    None
+                                },
+                            },
+                            _ => {
+                                locked_state.notice(&format!("There is no file offer {} to accept", number));
+                                None
+                            },
+                        }
+                    },
+                    commands::Input::Decline(number) | commands::Input::Cancel(number) => match locked_state.transfer_id(number) {
+                        Some(id) => {
+                            if let Some(upload) = locked_state.uploads.remove(&id) {
+                                locked_state.notice_in(super::Conversation::Direct(upload.user), &format!("Stopped sending {}", upload.name));
+                            }
+                            if let Some(download) = locked_state.downloads.remove(&id) {
+                                let text = match download.is_accepted() {
+                                    true => format!("Stopped receiving {}", download.name),
+                                    false => format!("Declined {}", download.name),
+                                };
+                                locked_state.notice_in(super::Conversation::Direct(download.user.clone()), &text);
+                                download.abort();
+                            }
+                            Some(super::TeamsMessage::FileCancel { id, reason: format!("{} stopped the transfer", username) })
+                        },
+                        None => {
+                            locked_state.notice(&format!("There is no file transfer {}", number));
+                            None
+                        },
+                    },
+                    commands::Input::Img(path) => match locked_state.selected.clone() {
+                        Some(conversation) => {
+                            // The art starts indented on its own line
+                            let max_width = locked_state.chat_width.saturating_sub(2).max(1) as u32;
+                            let art = crate::img_to_ascii_art::scale_for_width(&path, max_width)
+                                .and_then(|scale| crate::img_to_ascii_art::get_image(&path, scale));
+                            match art {
+                                Ok(art) => {
+                                    let parent = locked_state.selected_message;
+                                    locked_state.send(conversation, parent, art, super::MessageKind::Image)
+                                },
+                                Err(e) => {
+                                    locked_state.notice(&format!("Could not read the image {}: {}", path, e));
+                                    None
+                                },
+                            }
+                        },
+                        None => {
+                            locked_state.notice("Select a chat with Tab before sending an image");
+                            None
+                        },
+                    },
+                    commands::Input::Edit(text) => {
+                        let last = locked_state.selected.as_ref()
+                            .and_then(|selected| locked_state.last_own_message(selected))
+                            .map(|line| (line.id, line.content, line.message.clone(), line.encrypted));
+                        match last {
+                            None => {
+                                locked_state.notice("There is no message of yours in this chat to edit");
+                                None
+                            },
+                            Some((None, _, _, _)) => {
+                                locked_state.notice("Your last message is not sent yet, try again in a moment");
+                                None
+                            },
+                            Some((_, super::MessageKind::Image, _, _)) => {
+                                locked_state.notice("Images can not be edited, /delete it instead");
+                                None
+                            },
+                            // Without new text the old one is put into the message box to change it there
+                            Some((_, _, old, _)) if text.is_empty() => {
+                                locked_state.editor.insert_str(&format!("/edit {}", old));
+                                None
+                            },
+                            Some((Some(id), _, _, true)) => {
+                                let kind = if super::contains_code(&text) { super::MessageKind::Code } else { super::MessageKind::Text };
+                                let encrypted = match locked_state.selected.clone() {
+                                    Some(super::Conversation::Direct(user)) => locked_state.encrypt(&user, kind, &text),
+                                    _ => Err("Only direct messages can be encrypted".to_string()),
+                                };
+                                match encrypted {
+                                    Ok(encrypted) => Some(super::TeamsMessage::EditMessage(id, encrypted)),
+                                    Err(reason) => {
+                                        locked_state.notice(&format!("Could not encrypt the new text: {}", reason));
+                                        None
+                                    },
+                                }
+                            },
+                            Some((Some(id), _, _, false)) => Some(super::TeamsMessage::EditMessage(id, text)),
+                        }
+                    },
+                    commands::Input::Delete => {
+                        let last = locked_state.selected.as_ref()
+                            .and_then(|selected| locked_state.last_own_message(selected))
+                            .map(|line| line.id);
+                        match last {
+                            Some(Some(id)) => Some(super::TeamsMessage::DeleteMessage(id)),
+                            Some(None) => {
+                                locked_state.notice("Your last message is not sent yet, try again in a moment");
+                                None
+                            },
+                            None => {
+                                locked_state.notice("There is no message of yours in this chat to delete");
+                                None
+                            },
+                        }
+                    },
+                    commands::Input::React(emoji) => locked_state.reaction_request(emoji),
+                    commands::Input::Encrypt(on) => match (locked_state.selected.clone(), on) {
+                        (Some(super::Conversation::Direct(_)), true) if locked_state.keys.is_none() => {
+                            locked_state.notice("You have no key, see the log why it could not be loaded");
+                            None
+                        },
+                        // The reply enables the encryption once the key is known
+                        (Some(super::Conversation::Direct(user)), true) => Some(super::TeamsMessage::KeyRequest(user)),
+                        (Some(super::Conversation::Direct(user)), false) => {
+                            locked_state.encrypted.remove(&user);
+                            locked_state.notice(&format!("Messages to {} are not encrypted anymore", user));
+                            None
+                        },
+                        _ => {
+                            locked_state.notice("Only direct messages can be encrypted, select the chat with a user first");
+                            None
+                        },
+                    },
+                    commands::Input::Fingerprint => {
+                        let own = match &locked_state.keys {
+                            Some(keys) => format!("Your fingerprint: {}", e2e::fingerprint(&keys.public_key())),
+                            None => "You have no key".to_string(),
+                        };
+                        locked_state.notice(&own);
+                        if let Some(super::Conversation::Direct(user)) = locked_state.selected.clone() {
+                            let text = match locked_state.peer_keys.get(&user) {
+                                Some(key) => Some(format!("Fingerprint of {}: {}", user, e2e::fingerprint(key))),
+                                None if !locked_state.changed_keys.contains_key(&user) => Some(format!("The key of {} is not known yet, /encrypt asks for it", user)),
+                                None => None,
+                            };
+                            if let Some(text) = text {
+                                locked_state.notice(&text);
+                            }
+                            if let Some(key) = locked_state.changed_keys.get(&user) {
+                                let text = format!("New key of {}, not used before /trust: {}", user, e2e::fingerprint(key));
+                                locked_state.notice(&text);
+                            }
+                        }
+                        None
+                    },
+                    commands::Input::Trust => {
+                        match locked_state.selected.clone() {
+                            Some(super::Conversation::Direct(user)) => locked_state.trust_key(&user),
+                            _ => locked_state.notice("Select the chat with a user first"),
+                        }
+                        None
+                    },
+                    commands::Input::Quit => {
+                        sx.send(Command::Quit).unwrap();
+                        None
+                    },
+                    commands::Input::Help => {
+                        for line in commands::HELP {
+                            locked_state.notice(line);
+                        }
+                        None
+                    },
+                    commands::Input::Usage(usage) => {
+                        locked_state.notice(&format!("Usage: {}", usage));
+                        None
+                    },
+                    commands::Input::Unknown(command) => {
+                        locked_state.notice(&format!("Unknown command /{}, try /help", command));
+                        None
+                    },
                 }
             },
             Command::Reinit => {
-                let mut connection_ref = connection.lock().unwrap();
-                let mut success = false;
+                state.lock().unwrap().abort_transfers("the connection to the server was lost");
+                // A connection that only looks dead would keep the user logged in on the server.
+                // The old one stays in place meanwhile, so the other threads fail fast instead of waiting for the lock.
+                if let Err(e) = connection.lock().unwrap().stream.shutdown() {
+                    log::info!("Could not close the old connection {:?}", e);
+                }
+                let mut reconnected = None;
                 for i in 1..5 {
-                    if let Ok(tcp_stream) = std::net::TcpStream::connect("127.0.0.1:7474") {
-                        let mut tcp_stream = tcp_stream;
-                        let message = super::TeamsMessage::NewUser(username.clone());
-                        super::send(&message, &mut tcp_stream).unwrap();
-                        tcp_stream.set_nonblocking(true).unwrap();
-                        *connection_ref = tcp_stream;
-                        success = true;
-                        break;
+                    if let Ok(stream) = connector.connect(&config.connect) {
+                        let mut new_connection = super::Connection::new(stream);
+                        // The session token saves asking for the password again
+                        let credentials = super::Credentials::Token { user: username.clone(), token: token.clone() };
+                        match log_in(&mut new_connection, credentials) {
+                            Ok(super::LoginReply::Accepted(_)) => {
+                                new_connection.stream.set_nonblocking(true).unwrap();
+                                if let Err(e) = request_overview(&mut new_connection.stream, public_key.as_deref()) {
+                                    log::error!("Could not request the overview {:?}", e);
+                                }
+                                reconnected = Some(new_connection);
+                                break;
+                            },
+                            // The server might not have noticed yet that the old connection is gone
+                            Ok(super::LoginReply::Rejected(reason)) => log::warn!("Reconnect rejected: {}", reason),
+                            Err(e) => log::error!("Could not log in again {:?}", e),
+                        }
                     }
 
-                    println!("Could not reconnect, trying again in {i} seconds...");
+                    state.lock().unwrap().status = format!("!! Could not reconnect, trying again in {i} seconds... !!");
                     std::thread::sleep(std::time::Duration::from_secs(i));
                 }
 
-                if success {
+                if let Some(new_connection) = reconnected {
+                    let mut connection_ref = connection.lock().unwrap();
+                    *connection_ref = new_connection;
+                    // The server only stores a message once, even if it was acknowledged and only the ack got lost
+                    let mut locked_state = state.lock().unwrap();
+                    locked_state.status.clear();
+                    locked_state.connection = ConnectionStatus::Connected;
+                    for request in locked_state.unacknowledged() {
+                        if let Err(e) = super::send(&request, &mut connection_ref.stream) {
+                            log::error!("Could not send again {:?}", e);
+                            break;
+                        }
+                    }
                     continue;
                 }
 
                 log::info!("Could not reconnect after timout, exiting");
+                restore_terminal();
                 std::process::exit(0);
             },
+        };
+
+        if let Some(request) = request {
+            if super::send(&request, &mut connection.lock().unwrap().stream).is_err() && state.lock().unwrap().connection_lost() {
+                sx.send(Command::Reinit).unwrap();
+            }
+        }
+        // Whatever happened, new messages may be shown now
+        let receipts = state.lock().unwrap().read_receipts();
+        for receipt in receipts {
+            if let Err(e) = super::send(&receipt, &mut connection.lock().unwrap().stream) {
+                log::error!("Could not send the read receipt {:?}", e);
+                break;
+            }
         }
     }
 
+    restore_terminal();
+    println!("Exiting...");
     Ok(())
 }
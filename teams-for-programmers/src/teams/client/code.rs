@@ -0,0 +1,180 @@
+use syntect::easy::HighlightLines;
+use syntect::highlighting::ThemeSet;
+use syntect::parsing::SyntaxSet;
+
+const THEME: &str = "base16-ocean.dark";
+const TAB: &str = "    ";
+
+/// A part of a message, either plain text or the content of a ``` block
+#[derive(Debug, PartialEq, Eq)]
+pub enum Segment<'a> {
+    Text(&'a str),
+    Code { language: &'a str, code: &'a str },
+}
+
+/// Splits the text at ``` fences. The fence has to start a line and may be followed by the language.
+/// An unterminated block runs until the end of the text.
+pub fn split(text: &str) -> Vec<Segment<'_>> {
+    let mut segments = vec![];
+    let mut rest = text;
+    while let Some(fence) = super::super::find_fence(rest) {
+        let text = rest[..fence].trim_end();
+        if !text.is_empty() {
+            segments.push(Segment::Text(text));
+        }
+
+        let after_fence = &rest[fence + super::super::CODE_FENCE.len()..];
+        let (language, body) = match after_fence.split_once('\n') {
+            Some((language, body)) => (language.trim(), body),
+            None => (after_fence.trim(), ""),
+        };
+        let (code, next) = match super::super::find_fence(body) {
+            Some(end) => {
+                let after_end = &body[end + super::super::CODE_FENCE.len()..];
+                (&body[..end], after_end.split_once('\n').map(|(_, next)| next).unwrap_or(""))
+            },
+            None => (body, ""),
+        };
+        segments.push(Segment::Code { language, code: code.trim_end_matches('\n') });
+        rest = next;
+    }
+    if !rest.is_empty() {
+        segments.push(Segment::Text(rest));
+    }
+    segments
+}
+
+fn syntax_set() -> &'static SyntaxSet {
+    static SYNTAX_SET: std::sync::OnceLock<SyntaxSet> = std::sync::OnceLock::new();
+    SYNTAX_SET.get_or_init(SyntaxSet::load_defaults_newlines)
+}
+
+fn theme_set() -> &'static ThemeSet {
+    static THEME_SET: std::sync::OnceLock<ThemeSet> = std::sync::OnceLock::new();
+    THEME_SET.get_or_init(ThemeSet::load_defaults)
+}
+
+fn to_tui_style(style: syntect::highlighting::Style) -> tui::style::Style {
+    let color = style.foreground;
+    let mut tui_style = tui::style::Style::default().fg(tui::style::Color::Rgb(color.r, color.g, color.b));
+    if style.font_style.contains(syntect::highlighting::FontStyle::BOLD) {
+        tui_style = tui_style.add_modifier(tui::style::Modifier::BOLD);
+    }
+    if style.font_style.contains(syntect::highlighting::FontStyle::ITALIC) {
+        tui_style = tui_style.add_modifier(tui::style::Modifier::ITALIC);
+    }
+    tui_style
+}
+
+/// Renders the code with line numbers, highlighted for the language if it is known.
+/// Lines longer than `width` continue on the next line behind an empty gutter.
+pub fn highlight(language: &str, code: &str, width: usize) -> Vec<tui::text::Spans<'static>> {
+    let syntax_set = syntax_set();
+    let syntax = syntax_set.find_syntax_by_token(language)
+        .unwrap_or_else(|| syntax_set.find_syntax_plain_text());
+    let mut highlighter = HighlightLines::new(syntax, &theme_set().themes[THEME]);
+
+    let line_count = code.lines().count().max(1);
+    let number_width = line_count.to_string().len();
+    let gutter_style = tui::style::Style::default().fg(tui::style::Color::DarkGray);
+    let code_width = width.saturating_sub(number_width + 3).max(1);
+
+    let mut rendered = vec![];
+    for (number, line) in code.lines().enumerate() {
+        let line = line.replace('\t', TAB);
+        let mut line_with_newline = line.clone();
+        line_with_newline.push('\n');
+        let regions = match highlighter.highlight_line(&line_with_newline, syntax_set) {
+            Ok(regions) => regions,
+            Err(e) => {
+                log::error!("Could not highlight {:?}", e);
+                vec![(syntect::highlighting::Style::default(), line_with_newline.as_str())]
+            },
+        };
+
+        let mut row = vec![tui::text::Span::styled(format!("{:>width$} │ ", number + 1, width = number_width), gutter_style)];
+        let mut row_width = 0;
+        for (style, text) in regions {
+            let style = to_tui_style(style);
+            let mut part = String::new();
+            for c in text.trim_end_matches('\n').chars() {
+                let char_width = unicode_width::UnicodeWidthChar::width(c).unwrap_or(0);
+                if row_width + char_width > code_width {
+                    row.push(tui::text::Span::styled(std::mem::take(&mut part), style));
+                    rendered.push(tui::text::Spans::from(std::mem::take(&mut row)));
+                    row.push(tui::text::Span::styled(format!("{:>width$} ┆ ", "", width = number_width), gutter_style));
+                    row_width = 0;
+                }
+                part.push(c);
+                row_width += char_width;
+            }
+            if !part.is_empty() {
+                row.push(tui::text::Span::styled(part, style));
+            }
+        }
+        rendered.push(tui::text::Spans::from(row));
+    }
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn code<'a>(language: &'a str, code: &'a str) -> Segment<'a> {
+        Segment::Code { language, code }
+    }
+
+    #[test]
+    fn text_without_fences() {
+        assert_eq!(split("just text\nover lines"), vec![Segment::Text("just text\nover lines")]);
+        assert_eq!(split(""), vec![]);
+    }
+
+    #[test]
+    fn language_tag() {
+        assert_eq!(split("```rust \nfn main() {}\n```"), vec![code("rust", "fn main() {}")]);
+        assert_eq!(split("```\nplain\n```"), vec![code("", "plain")]);
+    }
+
+    #[test]
+    fn unterminated_fence_runs_to_the_end() {
+        assert_eq!(split("look:\n```js\nlet a = 1;\n\nlet b = 2;\n"), vec![
+            Segment::Text("look:"),
+            code("js", "let a = 1;\n\nlet b = 2;"),
+        ]);
+        assert_eq!(split("```"), vec![code("", "")]);
+    }
+
+    #[test]
+    fn fence_has_to_start_a_line() {
+        for text in ["use ``` for code", "inline ```rust\nlet a = 1;```", "a `` b"] {
+            assert_eq!(split(text), vec![Segment::Text(text)], "{}", text);
+        }
+        // Leading spaces are allowed
+        assert_eq!(split("see\n  ```sh\nls\n```"), vec![Segment::Text("see"), code("sh", "ls")]);
+    }
+
+    #[test]
+    fn text_between_blocks() {
+        assert_eq!(split("first\n```\na\n```\nbetween\n```py\nb\n```\nlast"), vec![
+            Segment::Text("first"),
+            code("", "a"),
+            Segment::Text("between"),
+            code("py", "b"),
+            Segment::Text("last"),
+        ]);
+        // Text on the line of the closing fence is dropped with it
+        assert_eq!(split("```\na\n``` trailing\nafter"), vec![code("", "a"), Segment::Text("after")]);
+    }
+
+    #[test]
+    fn highlighted_lines_are_numbered_and_wrapped() {
+        let lines = highlight("rust", "let a = 1;\nlet b = 2;", 80);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].0[0].content, "2 │ ");
+        let wrapped = highlight("", "0123456789", 8);
+        let text: Vec<String> = wrapped.iter().map(|line| line.0.iter().map(|span| span.content.as_ref()).collect()).collect();
+        assert_eq!(text, vec!["1 │ 0123", "  ┆ 4567", "  ┆ 89"]);
+    }
+}
@@ -0,0 +1,230 @@
+/// What the user typed into the message box
+#[derive(Debug, PartialEq, Eq)]
+pub enum Input {
+    /// Plain text for the selected conversation
+    Text(String),
+    Msg { target: String, text: String },
+    Create(String),
+    Join(String),
+    Leave(String),
+    Channels,
+    Who,
+    Nick(String),
+    /// New text for the own last message in the selected conversation, empty to load the old text
+    Edit(String),
+    Delete,
+    /// Toggles the reaction on the selected message
+    React(String),
+    /// Turns end-to-end encryption of the selected direct conversation on or off
+    Encrypt(bool),
+    /// Shows the fingerprints of the own key and of the key of the selected user
+    Fingerprint,
+    /// Trusts the new key of the selected user and encrypts the messages to them with it
+    Trust,
+    /// Path of an image to send as ascii art
+    Img(String),
+    /// Path of a file to offer to the selected user
+    Send(String),
+    /// Transfers are referred to by their short number
+    Accept(usize),
+    Decline(usize),
+    Cancel(usize),
+    Quit,
+    Help,
+    /// A known command with wrong arguments, holds the usage
+    Usage(&'static str),
+    Unknown(String),
+}
+
+pub const HELP: &[&str] = &[
+    "/msg <user|#channel> <text>  send a message and open the conversation",
+    "/create <#channel>           create a channel and join it",
+    "/join <#channel>             join a channel",
+    "/leave [#channel]            leave the given or the selected channel",
+    "/channels                    list all channels",
+    "/who                         list the online users",
+    "/nick <name>                 change the username",
+    "/edit [text]                 replace your last message here, without text it is loaded for editing",
+    "/delete                      delete your last message here",
+    "/react <emoji>               react to the selected message, again to take it back",
+    "/encrypt [on|off]            end-to-end encrypt the messages to the selected user",
+    "/fingerprint                 show the key fingerprints to compare with the selected user",
+    "/trust                       use the new key of the selected user, after comparing its fingerprint with them",
+    "/img <path>                  send an image as ascii art to the selected chat",
+    "/send <path>                 offer a file to the selected user",
+    "/accept <number>             accept a file, it is saved to the downloads directory",
+    "/decline <number>            decline a file",
+    "/cancel <number>             stop sending or receiving a file",
+    "/quit                        leave teams",
+    "/help                        show this help",
+    "Plain text goes to the selected chat, start it with // to send a leading /",
+    "Alt+Up/Alt+Down select a message, Esc clears the selection, Alt+1 to Alt+5 react with 👍 🎉 😂 👀 🚀",
+];
+
+fn channel_name(name: &str) -> String {
+    name.trim_start_matches('#').to_string()
+}
+
+pub fn parse(input: &str) -> Input {
+    let command_line = match input.strip_prefix('/') {
+        Some(command_line) if !command_line.starts_with('/') => command_line,
+        // "//" escapes a leading slash
+        Some(text) => return Input::Text(text.to_string()),
+        None => return Input::Text(input.to_string()),
+    };
+
+    let (command, arguments) = match command_line.split_once(char::is_whitespace) {
+        Some((command, arguments)) => (command, arguments.trim_start()),
+        None => (command_line, ""),
+    };
+    let words: Vec<&str> = arguments.split_whitespace().collect();
+
+    match command {
+        "msg" | "m" => match arguments.split_once(char::is_whitespace) {
+            Some((target, text)) if !text.trim().is_empty() => Input::Msg {
+                target: target.to_string(),
+                text: text.to_string(),
+            },
+            _ => Input::Usage("/msg <user|#channel> <text>"),
+        },
+        "create" => match words.as_slice() {
+            [channel] => Input::Create(channel_name(channel)),
+            _ => Input::Usage("/create <#channel>"),
+        },
+        "join" | "j" => match words.as_slice() {
+            [channel] => Input::Join(channel_name(channel)),
+            _ => Input::Usage("/join <#channel>"),
+        },
+        "leave" | "part" => match words.as_slice() {
+            [] => Input::Leave(String::new()),
+            [channel] => Input::Leave(channel_name(channel)),
+            _ => Input::Usage("/leave [#channel]"),
+        },
+        "channels" | "list" if words.is_empty() => Input::Channels,
+        "who" if words.is_empty() => Input::Who,
+        "nick" => match words.as_slice() {
+            [name] => Input::Nick(name.to_string()),
+            _ => Input::Usage("/nick <name>"),
+        },
+        "edit" | "e" => Input::Edit(arguments.trim_end().to_string()),
+        "delete" | "del" if words.is_empty() => Input::Delete,
+        "delete" | "del" => Input::Usage("/delete"),
+        "react" | "r" => match words.as_slice() {
+            [emoji] => Input::React(emoji.to_string()),
+            _ => Input::Usage("/react <emoji>"),
+        },
+        "encrypt" => match words.as_slice() {
+            [] | ["on"] => Input::Encrypt(true),
+            ["off"] => Input::Encrypt(false),
+            _ => Input::Usage("/encrypt [on|off]"),
+        },
+        "fingerprint" | "fp" if words.is_empty() => Input::Fingerprint,
+        "fingerprint" | "fp" => Input::Usage("/fingerprint"),
+        "trust" if words.is_empty() => Input::Trust,
+        "trust" => Input::Usage("/trust"),
+        "img" if !arguments.is_empty() => Input::Img(arguments.trim_end().to_string()),
+        "img" => Input::Usage("/img <path>"),
+        "send" if !arguments.is_empty() => Input::Send(arguments.trim_end().to_string()),
+        "send" => Input::Usage("/send <path>"),
+        "accept" => match words.as_slice() {
+            [number] if number.parse::<usize>().is_ok() => Input::Accept(number.parse().unwrap()),
+            _ => Input::Usage("/accept <number>"),
+        },
+        "decline" => match words.as_slice() {
+            [number] if number.parse::<usize>().is_ok() => Input::Decline(number.parse().unwrap()),
+            _ => Input::Usage("/decline <number>"),
+        },
+        "cancel" => match words.as_slice() {
+            [number] if number.parse::<usize>().is_ok() => Input::Cancel(number.parse().unwrap()),
+            _ => Input::Usage("/cancel <number>"),
+        },
+        "quit" | "exit" if words.is_empty() => Input::Quit,
+        "help" | "?" => Input::Help,
+        "channels" | "list" => Input::Usage("/channels"),
+        "who" => Input::Usage("/who"),
+        "quit" | "exit" => Input::Usage("/quit"),
+        _ => Input::Unknown(command.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_text_and_escaped_slash() {
+        assert_eq!(parse("hello /world"), Input::Text("hello /world".to_string()));
+        assert_eq!(parse(" /msg bob hi"), Input::Text(" /msg bob hi".to_string()));
+        assert_eq!(parse("//msg bob hi"), Input::Text("/msg bob hi".to_string()));
+        assert_eq!(parse("//"), Input::Text("/".to_string()));
+    }
+
+    #[test]
+    fn unknown_commands() {
+        assert_eq!(parse("/frobnicate now"), Input::Unknown("frobnicate".to_string()));
+        assert_eq!(parse("/usr/bin/env is where it is"), Input::Unknown("usr/bin/env".to_string()));
+        assert_eq!(parse("/MSG bob hi"), Input::Unknown("MSG".to_string()));
+        assert_eq!(parse("/"), Input::Unknown(String::new()));
+        assert_eq!(parse("/ some text"), Input::Unknown(String::new()));
+    }
+
+    #[test]
+    fn arguments() {
+        assert_eq!(parse("/msg bob  hi  there"), Input::Msg { target: "bob".to_string(), text: " hi  there".to_string() });
+        assert_eq!(parse("/m #rust hi"), Input::Msg { target: "#rust".to_string(), text: "hi".to_string() });
+        assert_eq!(parse("/join #rust"), Input::Join("rust".to_string()));
+        assert_eq!(parse("/create\trust"), Input::Create("rust".to_string()));
+        assert_eq!(parse("/leave"), Input::Leave(String::new()));
+        assert_eq!(parse("/edit  new text "), Input::Edit("new text".to_string()));
+        assert_eq!(parse("/edit"), Input::Edit(String::new()));
+        assert_eq!(parse("/encrypt"), Input::Encrypt(true));
+        assert_eq!(parse("/encrypt off"), Input::Encrypt(false));
+        assert_eq!(parse("/trust"), Input::Trust);
+        assert_eq!(parse("/send my file.txt"), Input::Send("my file.txt".to_string()));
+        assert_eq!(parse("/accept 2"), Input::Accept(2));
+        assert_eq!(parse("/quit"), Input::Quit);
+        assert_eq!(parse("/help me"), Input::Help);
+    }
+
+    #[test]
+    fn missing_arguments() {
+        for (input, usage) in [
+            ("/msg", "/msg <user|#channel> <text>"),
+            ("/msg bob", "/msg <user|#channel> <text>"),
+            ("/msg bob   ", "/msg <user|#channel> <text>"),
+            ("/create", "/create <#channel>"),
+            ("/join", "/join <#channel>"),
+            ("/nick", "/nick <name>"),
+            ("/react", "/react <emoji>"),
+            ("/img", "/img <path>"),
+            ("/send", "/send <path>"),
+            ("/accept", "/accept <number>"),
+            ("/decline", "/decline <number>"),
+            ("/cancel", "/cancel <number>"),
+        ] {
+            assert_eq!(parse(input), Input::Usage(usage), "{}", input);
+        }
+    }
+
+    #[test]
+    fn extra_or_wrong_arguments() {
+        for (input, usage) in [
+            ("/create a b", "/create <#channel>"),
+            ("/join a b", "/join <#channel>"),
+            ("/leave a b", "/leave [#channel]"),
+            ("/nick new name", "/nick <name>"),
+            ("/delete now", "/delete"),
+            ("/react 👍 🎉", "/react <emoji>"),
+            ("/encrypt maybe", "/encrypt [on|off]"),
+            ("/fingerprint bob", "/fingerprint"),
+            ("/trust bob", "/trust"),
+            ("/accept one", "/accept <number>"),
+            ("/cancel -1", "/cancel <number>"),
+            ("/channels all", "/channels"),
+            ("/who is there", "/who"),
+            ("/quit now", "/quit"),
+        ] {
+            assert_eq!(parse(input), Input::Usage(usage), "{}", input);
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::io::Write;
+use base64::Engine;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use super::super::MessageKind;
+
+/// Mixed into the shared secret, so the message key is only used for this
+const KEY_CONTEXT: &[u8] = b"teams end-to-end message key v1";
+const NONCE_LEN: usize = 12;
+/// Bytes of the key hash shown as fingerprint
+const FINGERPRINT_LEN: usize = 16;
+
+/// What the server gets instead of the text. Both public keys are part of it, so the sender
+/// can read the own messages in the history as well, even after the peer got a new key.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    sender: String,
+    recipient: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// The encrypted part of the envelope
+#[derive(Serialize, Deserialize)]
+struct Content {
+    kind: MessageKind,
+    message: String,
+}
+
+/// The x25519 key pair of the user. The secret key never leaves the key file.
+pub struct KeyPair {
+    secret: x25519_dalek::StaticSecret,
+    public: x25519_dalek::PublicKey,
+}
+
+fn encode(bytes: &[u8]) -> String {
+    base64::engine::general_purpose::STANDARD.encode(bytes)
+}
+
+fn decode_key(key: &str) -> Option<[u8; 32]> {
+    base64::engine::general_purpose::STANDARD.decode(key.trim()).ok()?.try_into().ok()
+}
+
+pub fn is_valid_key(key: &str) -> bool {
+    decode_key(key).is_some()
+}
+
+/// Hash of the public key to compare with the peer over another way, like "1a2b 3c4d ..."
+pub fn fingerprint(key: &str) -> String {
+    let bytes = decode_key(key).map(|bytes| bytes.to_vec()).unwrap_or_else(|| key.as_bytes().to_vec());
+    let hash = sha2::Sha256::digest(&bytes);
+    hash[..FINGERPRINT_LEN].chunks(2)
+        .map(|pair| format!("{:02x}{:02x}", pair[0], pair[1]))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Creates the file only readable by the owner, if the system knows about that
+fn write_secret(path: &std::path::Path, content: &str) -> Result<(), std::io::Error> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(path)?.write_all(content.as_bytes())
+}
+
+impl KeyPair {
+    /// Reads the secret key from the file, or creates the file with a new one
+    pub fn load_or_create(path: &std::path::Path) -> Result<KeyPair, std::io::Error> {
+        let secret = match std::fs::read_to_string(path) {
+            Ok(content) => match decode_key(&content) {
+                Some(bytes) => x25519_dalek::StaticSecret::from(bytes),
+                None => return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid key file {:?}", path))),
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                let secret = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+                if let Some(directory) = path.parent() {
+                    std::fs::create_dir_all(directory)?;
+                }
+                write_secret(path, &encode(secret.as_bytes()))?;
+                log::info!("Created a new key in {:?}", path);
+                secret
+            },
+            Err(e) => return Err(e),
+        };
+        let public = x25519_dalek::PublicKey::from(&secret);
+        Ok(KeyPair { secret, public })
+    }
+
+    pub fn public_key(&self) -> String {
+        encode(self.public.as_bytes())
+    }
+
+    fn cipher(&self, peer: [u8; 32]) -> chacha20poly1305::ChaCha20Poly1305 {
+        let shared = self.secret.diffie_hellman(&x25519_dalek::PublicKey::from(peer));
+        let mut hasher = sha2::Sha256::new();
+        hasher.update(KEY_CONTEXT);
+        hasher.update(shared.as_bytes());
+        chacha20poly1305::ChaCha20Poly1305::new(&hasher.finalize())
+    }
+
+    /// The text to send instead of the message, only the owner of `peer_key` and we can read it
+    pub fn encrypt(&self, peer_key: &str, kind: MessageKind, message: &str) -> Result<String, String> {
+        let peer = decode_key(peer_key).ok_or_else(|| "The public key of the peer is invalid".to_string())?;
+        let content = serde_json::to_vec(&Content { kind, message: message.to_string() }).expect("Could not serialize the message!");
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let sender = self.public_key();
+        let recipient = encode(&peer);
+        // The keys are authenticated as well, so nobody can claim that another key was used
+        let associated = format!("{}{}", sender, recipient);
+        let payload = chacha20poly1305::aead::Payload { msg: &content, aad: associated.as_bytes() };
+        let ciphertext = self.cipher(peer)
+            .encrypt(chacha20poly1305::Nonce::from_slice(&nonce), payload)
+            .map_err(|_| "Could not encrypt the message".to_string())?;
+        let envelope = Envelope { sender, recipient, nonce: encode(&nonce), ciphertext: encode(&ciphertext) };
+        Ok(serde_json::to_string(&envelope).expect("Could not serialize the envelope!"))
+    }
+
+    /// Decrypts a message of the conversation, `sent` tells whether we sent it or the peer did.
+    /// Returns the content together with the public key of the peer it was encrypted with.
+    pub fn decrypt(&self, message: &str, sent: bool) -> Result<(MessageKind, String, String), String> {
+        let envelope: Envelope = serde_json::from_str(message).map_err(|_| "The message is not encrypted properly".to_string())?;
+        let own = self.public_key();
+        // A message of the peer has to be for our key. Otherwise anybody could encrypt one "from us" to
+        // a key of their choice, which would then be taken as the key of the peer.
+        let peer_key = match sent {
+            true if envelope.sender == own => &envelope.recipient,
+            false if envelope.recipient == own => &envelope.sender,
+            _ => return Err("The message was encrypted for another key of yours".to_string()),
+        };
+        let peer = decode_key(peer_key).ok_or_else(|| "The public key of the peer is invalid".to_string())?;
+        let nonce = base64::engine::general_purpose::STANDARD.decode(&envelope.nonce).ok()
+            .filter(|nonce| nonce.len() == NONCE_LEN)
+            .ok_or_else(|| "The message is not encrypted properly".to_string())?;
+        let ciphertext = base64::engine::general_purpose::STANDARD.decode(&envelope.ciphertext)
+            .map_err(|_| "The message is not encrypted properly".to_string())?;
+        let associated = format!("{}{}", envelope.sender, envelope.recipient);
+        let payload = chacha20poly1305::aead::Payload { msg: &ciphertext, aad: associated.as_bytes() };
+        let content = self.cipher(peer)
+            .decrypt(chacha20poly1305::Nonce::from_slice(&nonce), payload)
+            .map_err(|_| "The message could not be decrypted, it was changed on the way".to_string())?;
+        let content: Content = serde_json::from_slice(&content).map_err(|_| "The decrypted message is broken".to_string())?;
+        let kind = match content.kind {
+            MessageKind::Encrypted => MessageKind::Text,
+            kind => kind,
+        };
+        Ok((kind, content.message, peer_key.clone()))
+    }
+}
+
+/// Public keys of other users the own user trusts, kept in a file next to the own key.
+/// After a restart the server can not hand out another key unnoticed.
+#[derive(Default)]
+pub struct KnownKeys {
+    /// None if the keys are only known for this session
+    path: Option<std::path::PathBuf>,
+    keys: std::collections::HashMap<String, String>,
+}
+
+impl KnownKeys {
+    /// Reads the keys trusted before, a missing file means none are
+    pub fn load(path: &std::path::Path) -> Result<KnownKeys, std::io::Error> {
+        let keys = match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid known keys {:?} {:?}", path, e)))?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => std::collections::HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(KnownKeys { path: Some(path.to_path_buf()), keys })
+    }
+
+    pub fn get(&self, user: &str) -> Option<&String> {
+        self.keys.get(user)
+    }
+
+    /// Uses the key for the user from now on, also after a restart
+    pub fn trust(&mut self, user: &str, key: String) {
+        self.keys.insert(user.to_string(), key);
+        self.save();
+    }
+
+    pub fn rename(&mut self, old: &str, new: &str) {
+        if let Some(key) = self.keys.remove(old) {
+            self.keys.insert(new.to_string(), key);
+            self.save();
+        }
+    }
+
+    /// Moves the file along when the own user is renamed
+    pub fn move_to(&mut self, path: &std::path::Path) {
+        if let Some(old) = &self.path {
+            if let Err(e) = std::fs::rename(old, path) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    log::error!("Could not rename the known keys {:?}", e);
+                }
+            }
+            self.path = Some(path.to_path_buf());
+        }
+    }
+
+    fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let content = serde_json::to_string_pretty(&self.keys).expect("Could not serialize the known keys!");
+        let mut temporary_name = path.file_name().unwrap_or_default().to_os_string();
+        temporary_name.push(".tmp");
+        let temporary = path.with_file_name(temporary_name);
+        if let Err(e) = std::fs::write(&temporary, content).and_then(|_| std::fs::rename(&temporary, path)) {
+            log::error!("Could not write the known keys to {:?} {:?}", path, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A directory for key files in the temp directory, removed again at the end of the test
+    struct TempDir(std::path::PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> TempDir {
+            let path = std::env::temp_dir().join(format!("teams-e2e-{}-{}", std::process::id(), name));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        fn key_pair(&self, user: &str) -> KeyPair {
+            KeyPair::load_or_create(&self.0.join(format!("{}.key", user))).unwrap()
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn both_sides_can_decrypt() {
+        let directory = TempDir::new("decrypt");
+        let alice = directory.key_pair("alice");
+        let bob = directory.key_pair("bob");
+        let message = alice.encrypt(&bob.public_key(), MessageKind::Text, "hi").unwrap();
+        let (kind, text, peer_key) = bob.decrypt(&message, false).unwrap();
+        assert_eq!((kind, text.as_str(), peer_key), (MessageKind::Text, "hi", alice.public_key()));
+        let (_, text, peer_key) = alice.decrypt(&message, true).unwrap();
+        assert_eq!((text.as_str(), peer_key), ("hi", bob.public_key()));
+        // Taken as sent by the other side, the keys do not fit
+        assert!(bob.decrypt(&message, true).is_err());
+        assert!(alice.decrypt(&message, false).is_err());
+    }
+
+    #[test]
+    fn trusted_keys_survive_a_restart() {
+        let directory = TempDir::new("known");
+        let path = directory.0.join("alice.known");
+        let mut known = KnownKeys::load(&path).unwrap();
+        assert_eq!(known.get("bob"), None);
+        known.trust("bob", "bob key".to_string());
+        known.trust("carol", "carol key".to_string());
+        known.rename("carol", "caroline");
+
+        let renamed = directory.0.join("alicia.known");
+        known.move_to(&renamed);
+        let known = KnownKeys::load(&renamed).unwrap();
+        assert_eq!(known.get("bob"), Some(&"bob key".to_string()));
+        assert_eq!(known.get("carol"), None);
+        assert_eq!(known.get("caroline"), Some(&"carol key".to_string()));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn broken_known_keys_are_an_error() {
+        let directory = TempDir::new("broken");
+        let path = directory.0.join("alice.known");
+        std::fs::write(&path, "{\"bob\": ").unwrap();
+        assert!(KnownKeys::load(&path).is_err());
+    }
+}
@@ -0,0 +1,410 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Upper bound for the remembered inputs
+const MAX_HISTORY: usize = 100;
+
+/// The text of the message box. The cursor is a byte index that always sits on a grapheme boundary.
+#[derive(Default)]
+pub struct LineEditor {
+    text: String,
+    cursor: usize,
+    /// Submitted inputs, oldest first
+    history: Vec<String>,
+    /// Position while browsing the history, None when editing a new input
+    history_index: Option<usize>,
+    /// The new input that was being edited before browsing the history
+    draft: String,
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum CharClass {
+    Whitespace,
+    Punctuation,
+    Word,
+}
+
+fn char_class(grapheme: &str) -> CharClass {
+    match grapheme.chars().next() {
+        Some(c) if c.is_whitespace() => CharClass::Whitespace,
+        Some(c) if c.is_alphanumeric() || c == '_' => CharClass::Word,
+        _ => CharClass::Punctuation,
+    }
+}
+
+impl LineEditor {
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Line and display column of the cursor
+    pub fn cursor_position(&self) -> (usize, usize) {
+        let before = &self.text[..self.cursor];
+        let line = before.matches('\n').count();
+        let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+        (line, unicode_width::UnicodeWidthStr::width(&before[line_start..]))
+    }
+
+    pub fn line_count(&self) -> usize {
+        self.text.matches('\n').count() + 1
+    }
+
+    pub fn insert_char(&mut self, c: char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += c.len_utf8();
+        self.history_index = None;
+    }
+
+    pub fn insert_str(&mut self, s: &str) {
+        // Terminals send \r for line breaks in pastes
+        let s = s.replace("\r\n", "\n").replace('\r', "\n");
+        self.text.insert_str(self.cursor, &s);
+        self.cursor += s.len();
+        self.history_index = None;
+    }
+
+    fn previous_boundary(&self, from: usize) -> Option<usize> {
+        self.text[..from].grapheme_indices(true).next_back().map(|(i, _)| i)
+    }
+
+    fn next_boundary(&self, from: usize) -> Option<usize> {
+        self.text[from..].graphemes(true).next().map(|g| from + g.len())
+    }
+
+    /// Start of the word before `from`, skipping whitespace first
+    fn previous_word_boundary(&self, from: usize) -> usize {
+        let mut graphemes = self.text[..from].grapheme_indices(true).rev().peekable();
+        while let Some((_, g)) = graphemes.peek() {
+            if char_class(g) != CharClass::Whitespace {
+                break;
+            }
+            graphemes.next();
+        }
+        let class = match graphemes.peek() {
+            Some((_, g)) => char_class(g),
+            None => return 0,
+        };
+        let mut boundary = 0;
+        for (i, g) in graphemes {
+            if char_class(g) != class {
+                return i + g.len();
+            }
+            boundary = i;
+        }
+        boundary
+    }
+
+    /// End of the word after `from`, skipping whitespace first
+    fn next_word_boundary(&self, from: usize) -> usize {
+        let mut graphemes = self.text[from..].grapheme_indices(true).peekable();
+        while let Some((_, g)) = graphemes.peek() {
+            if char_class(g) != CharClass::Whitespace {
+                break;
+            }
+            graphemes.next();
+        }
+        let class = match graphemes.peek() {
+            Some((_, g)) => char_class(g),
+            None => return self.text.len(),
+        };
+        for (i, g) in graphemes {
+            if char_class(g) != class {
+                return from + i;
+            }
+        }
+        self.text.len()
+    }
+
+    fn line_start(&self) -> usize {
+        self.text[..self.cursor].rfind('\n').map(|i| i + 1).unwrap_or(0)
+    }
+
+    fn line_end(&self) -> usize {
+        self.text[self.cursor..].find('\n').map(|i| self.cursor + i).unwrap_or(self.text.len())
+    }
+
+    pub fn backspace(&mut self) {
+        if let Some(start) = self.previous_boundary(self.cursor) {
+            self.text.replace_range(start..self.cursor, "");
+            self.cursor = start;
+        }
+    }
+
+    pub fn delete(&mut self) {
+        if let Some(end) = self.next_boundary(self.cursor) {
+            self.text.replace_range(self.cursor..end, "");
+        }
+    }
+
+    pub fn delete_word_backward(&mut self) {
+        let start = self.previous_word_boundary(self.cursor);
+        self.text.replace_range(start..self.cursor, "");
+        self.cursor = start;
+    }
+
+    pub fn delete_word_forward(&mut self) {
+        let end = self.next_word_boundary(self.cursor);
+        self.text.replace_range(self.cursor..end, "");
+    }
+
+    pub fn move_left(&mut self) {
+        if let Some(start) = self.previous_boundary(self.cursor) {
+            self.cursor = start;
+        }
+    }
+
+    pub fn move_right(&mut self) {
+        if let Some(end) = self.next_boundary(self.cursor) {
+            self.cursor = end;
+        }
+    }
+
+    pub fn move_word_left(&mut self) {
+        self.cursor = self.previous_word_boundary(self.cursor);
+    }
+
+    pub fn move_word_right(&mut self) {
+        self.cursor = self.next_word_boundary(self.cursor);
+    }
+
+    pub fn move_home(&mut self) {
+        self.cursor = self.line_start();
+    }
+
+    pub fn move_end(&mut self) {
+        self.cursor = self.line_end();
+    }
+
+    /// Moves the cursor to the same column of the line `target`, or to the end of it if it is shorter
+    fn move_to_line(&mut self, target: usize) {
+        let (_, column) = self.cursor_position();
+        let line_start = match target {
+            0 => 0,
+            _ => match self.text.match_indices('\n').nth(target - 1) {
+                Some((i, _)) => i + 1,
+                None => return,
+            },
+        };
+        let line = self.text[line_start..].split('\n').next().unwrap_or("");
+        let mut width = 0;
+        let mut offset = line.len();
+        for (i, g) in line.grapheme_indices(true) {
+            if width >= column {
+                offset = i;
+                break;
+            }
+            width += unicode_width::UnicodeWidthStr::width(g);
+        }
+        self.cursor = line_start + offset;
+    }
+
+    /// Moves a line up, or to the previous input of the history when already on the first line
+    pub fn up(&mut self) {
+        let (line, _) = self.cursor_position();
+        if line > 0 {
+            self.move_to_line(line - 1);
+            return;
+        }
+
+        let index = match self.history_index {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = self.text.clone();
+                self.history.len() - 1
+            },
+        };
+        self.history_index = Some(index);
+        self.text = self.history[index].clone();
+        self.cursor = self.text.len();
+    }
+
+    /// Moves a line down, or to the next input of the history when already on the last line
+    pub fn down(&mut self) {
+        let (line, _) = self.cursor_position();
+        if line + 1 < self.line_count() {
+            self.move_to_line(line + 1);
+            return;
+        }
+
+        let index = match self.history_index {
+            Some(index) => index + 1,
+            None => return,
+        };
+        if index < self.history.len() {
+            self.history_index = Some(index);
+            self.text = self.history[index].clone();
+        } else {
+            self.history_index = None;
+            self.text = std::mem::take(&mut self.draft);
+        }
+        self.cursor = self.text.len();
+    }
+
+    /// Empties the editor and returns the input, remembering it for the history
+    pub fn submit(&mut self) -> String {
+        let text = std::mem::take(&mut self.text);
+        self.cursor = 0;
+        self.history_index = None;
+        self.draft.clear();
+
+        if !text.trim().is_empty() && self.history.last() != Some(&text) {
+            if self.history.len() >= MAX_HISTORY {
+                self.history.remove(0);
+            }
+            self.history.push(text.clone());
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Editor with the text typed in, the cursor at its end
+    fn typed(text: &str) -> LineEditor {
+        let mut editor = LineEditor::default();
+        editor.insert_str(text);
+        editor
+    }
+
+    #[test]
+    fn backspace_and_delete_at_both_ends() {
+        let mut editor = LineEditor::default();
+        editor.backspace();
+        editor.delete();
+        assert_eq!(editor.text(), "");
+
+        let mut editor = typed("abc");
+        editor.delete();
+        assert_eq!(editor.text(), "abc");
+        editor.backspace();
+        assert_eq!(editor.text(), "ab");
+
+        editor.move_home();
+        editor.backspace();
+        assert_eq!(editor.text(), "ab");
+        editor.delete();
+        assert_eq!(editor.text(), "b");
+        assert_eq!(editor.cursor_position(), (0, 0));
+    }
+
+    #[test]
+    fn word_movement_across_punctuation_and_whitespace() {
+        let mut editor = typed("foo.bar  baz");
+        let mut stops = Vec::new();
+        for _ in 0..5 {
+            editor.move_word_left();
+            stops.push(editor.cursor_position().1);
+        }
+        assert_eq!(stops, vec![9, 4, 3, 0, 0]);
+
+        let mut stops = Vec::new();
+        for _ in 0..5 {
+            editor.move_word_right();
+            stops.push(editor.cursor_position().1);
+        }
+        assert_eq!(stops, vec![3, 4, 7, 12, 12]);
+    }
+
+    #[test]
+    fn word_deletion_across_punctuation_and_whitespace() {
+        let mut editor = typed("foo.bar  baz");
+        let mut texts = Vec::new();
+        for _ in 0..5 {
+            editor.delete_word_backward();
+            texts.push(editor.text().to_string());
+        }
+        assert_eq!(texts, vec!["foo.bar  ", "foo.", "foo", "", ""]);
+
+        let mut editor = typed("foo, bar");
+        editor.move_home();
+        let mut texts = Vec::new();
+        for _ in 0..4 {
+            editor.delete_word_forward();
+            texts.push(editor.text().to_string());
+        }
+        assert_eq!(texts, vec![", bar", " bar", "", ""]);
+    }
+
+    #[test]
+    fn combining_graphemes_are_edited_as_a_whole() {
+        // e with a combining acute accent
+        let mut editor = typed("ae\u{301}x");
+        editor.move_left();
+        editor.move_left();
+        assert_eq!(editor.cursor_position(), (0, 1));
+        editor.delete();
+        assert_eq!(editor.text(), "ax");
+
+        let mut editor = typed("hi 👨‍👩‍👧");
+        editor.backspace();
+        assert_eq!(editor.text(), "hi ");
+        editor.insert_str("ca\u{301}fe\u{301}");
+        editor.delete_word_backward();
+        assert_eq!(editor.text(), "hi ");
+    }
+
+    #[test]
+    fn wide_characters_take_two_columns() {
+        let mut editor = typed("漢字x");
+        assert_eq!(editor.cursor_position(), (0, 5));
+        editor.move_left();
+        editor.move_left();
+        assert_eq!(editor.cursor_position(), (0, 2));
+        editor.backspace();
+        assert_eq!(editor.text(), "字x");
+
+        // Up keeps the display column, not the byte offset
+        let mut editor = typed("漢字\nabcd");
+        editor.move_left();
+        editor.move_left();
+        editor.up();
+        assert_eq!(editor.cursor_position(), (0, 2));
+        editor.down();
+        assert_eq!(editor.cursor_position(), (1, 2));
+    }
+
+    #[test]
+    fn history_keeps_the_draft() {
+        let mut editor = LineEditor::default();
+        editor.up();
+        assert_eq!(editor.text(), "");
+
+        for input in ["first", "second", "second", "  "] {
+            editor.insert_str(input);
+            editor.submit();
+        }
+        editor.insert_str("draft");
+
+        let mut texts = Vec::new();
+        for _ in 0..3 {
+            editor.up();
+            texts.push(editor.text().to_string());
+        }
+        for _ in 0..3 {
+            editor.down();
+            texts.push(editor.text().to_string());
+        }
+        assert_eq!(texts, vec!["second", "first", "first", "second", "draft", "draft"]);
+        assert_eq!(editor.cursor_position(), (0, 5));
+    }
+
+    #[test]
+    fn up_and_down_move_between_lines_before_the_history() {
+        let mut editor = LineEditor::default();
+        editor.insert_str("old");
+        editor.submit();
+        editor.insert_str("one\r\ntwo");
+        assert_eq!(editor.line_count(), 2);
+
+        editor.up();
+        assert_eq!(editor.text(), "one\ntwo");
+        assert_eq!(editor.cursor_position(), (0, 3));
+        editor.up();
+        assert_eq!(editor.text(), "old");
+        editor.down();
+        assert_eq!(editor.text(), "one\ntwo");
+    }
+}
@@ -0,0 +1,365 @@
+use tui::style::{Color, Modifier, Style};
+use tui::text::{Span, Spans};
+
+const BULLET: &str = "• ";
+const BULLET_MARKERS: &[&str] = &["- ", "* ", "+ "];
+/// Characters a backslash turns into plain text
+const ESCAPABLE: &str = "\\`*_[]";
+
+fn code_style() -> Style {
+    Style::default().fg(Color::LightMagenta)
+}
+
+fn link_style() -> Style {
+    Style::default().fg(Color::Blue).add_modifier(Modifier::UNDERLINED)
+}
+
+/// Renders the text with *emphasis*, **bold**, `inline code`, [links](url) and bullet lists,
+/// wrapped to `width`. Continuation lines of a list item are indented behind the bullet.
+pub fn render(text: &str, width: usize) -> Vec<Spans<'static>> {
+    let width = width.max(1);
+    let mut lines = vec![];
+    for line in text.split('\n') {
+        match bullet(line) {
+            Some((depth, item)) => lines.extend(wrap(format!("{}{}", " ".repeat(depth), BULLET), inline(item), width)),
+            None => lines.extend(wrap(String::new(), inline(line), width)),
+        }
+    }
+    lines
+}
+
+/// Renders the inline markup of a single line without wrapping it
+pub fn inline(text: &str) -> Vec<Span<'static>> {
+    let mut spans = vec![];
+    parse(text, Style::default(), &mut spans);
+    spans
+}
+
+/// Indentation and content of a list item
+fn bullet(line: &str) -> Option<(usize, &str)> {
+    let item = line.trim_start_matches(' ');
+    let depth = line.len() - item.len();
+    BULLET_MARKERS.iter()
+        .find_map(|marker| item.strip_prefix(marker))
+        .map(|item| (depth, item))
+}
+
+/// Appends the text, merging it into the last span if that has the same style
+fn push(spans: &mut Vec<Span<'static>>, text: &str, style: Style) {
+    if text.is_empty() {
+        return;
+    }
+    match spans.last_mut() {
+        Some(last) if last.style == style => last.content.to_mut().push_str(text),
+        _ => spans.push(Span::styled(text.to_string(), style)),
+    }
+}
+
+/// End of an emphasis started by `delimiter`, relative to `body`. The closing delimiter
+/// may not follow whitespace, and `_` may not be inside a word like in snake_case.
+fn emphasis_end(body: &str, delimiter: char) -> Option<usize> {
+    let mut previous: Option<char> = None;
+    let mut chars = body.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        let next = chars.peek().map(|(_, next)| *next);
+        let closes = c == delimiter
+            && previous.map(|p| !p.is_whitespace()).unwrap_or(false)
+            && match delimiter {
+                '_' => !next.map(char::is_alphanumeric).unwrap_or(false),
+                _ => next != Some(delimiter),
+            };
+        if closes {
+            return Some(i);
+        }
+        previous = Some(c);
+    }
+    None
+}
+
+/// Length of a bare url at the start of the text, trailing punctuation is not part of it
+fn url_len(text: &str) -> Option<usize> {
+    if !text.starts_with("http://") && !text.starts_with("https://") {
+        return None;
+    }
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    Some(text[..end].trim_end_matches(|c| ".,;:!?)".contains(c)).len())
+}
+
+/// Parses the markup at the start of `rest` and returns how many bytes it used, 0 if there is none
+fn parse_markup(rest: &str, previous: Option<char>, style: Style, spans: &mut Vec<Span<'static>>) -> usize {
+    let word_start = !previous.map(char::is_alphanumeric).unwrap_or(false);
+    let first = match rest.chars().next() {
+        Some(first) => first,
+        None => return 0,
+    };
+    let after = &rest[first.len_utf8()..];
+    match first {
+        '\\' => match after.chars().next() {
+            Some(escaped) if ESCAPABLE.contains(escaped) => {
+                push(spans, &after[..escaped.len_utf8()], style);
+                1 + escaped.len_utf8()
+            },
+            _ => 0,
+        },
+        '`' => match after.find('`') {
+            Some(end) if end > 0 => {
+                push(spans, &after[..end], style.patch(code_style()));
+                end + 2
+            },
+            _ => 0,
+        },
+        '*' if after.starts_with('*') => {
+            let body = &after[1..];
+            // In "**a *b***" the bold ends with the last two stars
+            let end = body.char_indices()
+                .map(|(end, _)| end)
+                .find(|end| body[*end..].starts_with("**") && !body[end + 2..].starts_with('*'));
+            match end {
+                Some(end) if end > 0 && !body.starts_with(char::is_whitespace) => {
+                    parse(&body[..end], style.add_modifier(Modifier::BOLD), spans);
+                    end + 4
+                },
+                _ => 0,
+            }
+        },
+        delimiter @ ('*' | '_') if delimiter == '*' || word_start => {
+            if after.starts_with(char::is_whitespace) {
+                return 0;
+            }
+            match emphasis_end(after, delimiter) {
+                Some(end) => {
+                    parse(&after[..end], style.add_modifier(Modifier::ITALIC), spans);
+                    end + 2
+                },
+                None => 0,
+            }
+        },
+        '[' => {
+            let text_end = match after.find(']') {
+                Some(text_end) if text_end > 0 => text_end,
+                _ => return 0,
+            };
+            let target = match after[text_end + 1..].strip_prefix('(') {
+                Some(target) => target,
+                None => return 0,
+            };
+            let url = match target.find(')') {
+                Some(url_end) if url_end > 0 => &target[..url_end],
+                _ => return 0,
+            };
+            let text = &after[..text_end];
+            parse(text, style.patch(link_style()), spans);
+            if text != url {
+                push(spans, &format!(" ({})", url), style.fg(Color::DarkGray));
+            }
+            1 + text_end + 2 + url.len() + 1
+        },
+        'h' if word_start => match url_len(rest) {
+            Some(len) => {
+                push(spans, &rest[..len], style.patch(link_style()));
+                len
+            },
+            None => 0,
+        },
+        _ => 0,
+    }
+}
+
+fn parse(text: &str, style: Style, spans: &mut Vec<Span<'static>>) {
+    let mut rest = text;
+    let mut previous: Option<char> = None;
+    while let Some(c) = rest.chars().next() {
+        let used = match parse_markup(rest, previous, style, spans) {
+            0 => {
+                push(spans, &rest[..c.len_utf8()], style);
+                c.len_utf8()
+            },
+            used => used,
+        };
+        previous = rest[..used].chars().next_back();
+        rest = &rest[used..];
+    }
+}
+
+/// Parts of the spans between spaces. The space stays with the word in front of it.
+fn words(spans: Vec<Span<'static>>) -> Vec<Vec<(String, Style)>> {
+    let mut words = vec![];
+    let mut word = vec![];
+    for span in spans {
+        for piece in span.content.split_inclusive(' ') {
+            word.push((piece.to_string(), span.style));
+            if piece.ends_with(' ') {
+                words.push(std::mem::take(&mut word));
+            }
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+/// Wraps the spans at spaces, words longer than a line are broken anywhere.
+/// The first line starts with the prefix, the others with as many spaces.
+fn wrap(prefix: String, spans: Vec<Span<'static>>, width: usize) -> Vec<Spans<'static>> {
+    let indent = unicode_width::UnicodeWidthStr::width(prefix.as_str()).min(width / 2);
+    let mut lines = vec![];
+    let mut line = vec![];
+    push(&mut line, &prefix, Style::default());
+    let mut line_width = indent;
+
+    for word in words(spans) {
+        let word_width: usize = word.iter().map(|(text, _)| unicode_width::UnicodeWidthStr::width(text.as_str())).sum();
+        if line_width + word_width > width && line_width > indent {
+            lines.push(Spans::from(std::mem::take(&mut line)));
+            push(&mut line, &" ".repeat(indent), Style::default());
+            line_width = indent;
+        }
+        if line_width + word_width <= width {
+            for (text, style) in &word {
+                push(&mut line, text, *style);
+            }
+            line_width += word_width;
+            continue;
+        }
+        for (text, style) in &word {
+            for c in text.chars() {
+                let char_width = unicode_width::UnicodeWidthChar::width(c).unwrap_or(0);
+                if line_width + char_width > width && line_width > indent {
+                    lines.push(Spans::from(std::mem::take(&mut line)));
+                    push(&mut line, &" ".repeat(indent), Style::default());
+                    line_width = indent;
+                }
+                push(&mut line, c.encode_utf8(&mut [0; 4]), *style);
+                line_width += char_width;
+            }
+        }
+    }
+    lines.push(Spans::from(line));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn styled(spans: &[Span]) -> Vec<(String, Style)> {
+        spans.iter().map(|span| (span.content.to_string(), span.style)).collect()
+    }
+
+    fn plain(lines: &[Spans]) -> Vec<String> {
+        lines.iter()
+            .map(|line| line.0.iter().map(|span| span.content.as_ref()).collect())
+            .collect()
+    }
+
+    fn bold() -> Style {
+        Style::default().add_modifier(Modifier::BOLD)
+    }
+
+    fn italic() -> Style {
+        Style::default().add_modifier(Modifier::ITALIC)
+    }
+
+    #[test]
+    fn plain_text_is_one_span() {
+        assert_eq!(styled(&inline("just text")), vec![("just text".to_string(), Style::default())]);
+    }
+
+    #[test]
+    fn bold_and_emphasis() {
+        assert_eq!(styled(&inline("a **b** *c* _d_")), vec![
+            ("a ".to_string(), Style::default()),
+            ("b".to_string(), bold()),
+            (" ".to_string(), Style::default()),
+            ("c".to_string(), italic()),
+            (" ".to_string(), Style::default()),
+            ("d".to_string(), italic()),
+        ]);
+    }
+
+    #[test]
+    fn emphasis_inside_bold() {
+        assert_eq!(styled(&inline("**a *b***")), vec![
+            ("a ".to_string(), bold()),
+            ("b".to_string(), bold().add_modifier(Modifier::ITALIC)),
+        ]);
+    }
+
+    #[test]
+    fn identifiers_and_arithmetic_stay_plain() {
+        for text in ["snake_case_name", "2 * 3 * 4", "a ** b", "unterminated *star", "`"] {
+            assert_eq!(styled(&inline(text)), vec![(text.to_string(), Style::default())], "{}", text);
+        }
+    }
+
+    #[test]
+    fn inline_code_is_not_parsed() {
+        assert_eq!(styled(&inline("run `cargo *b*`")), vec![
+            ("run ".to_string(), Style::default()),
+            ("cargo *b*".to_string(), code_style()),
+        ]);
+    }
+
+    #[test]
+    fn escaped_markers() {
+        assert_eq!(styled(&inline(r"\*not\* \`code\`")), vec![("*not* `code`".to_string(), Style::default())]);
+    }
+
+    #[test]
+    fn non_ascii_text() {
+        for text in ["größer äöü", "日本語のテキスト", "🎉🚀 done", "👨‍👩‍👧", "é\u{301}", r"\ü", "ü*", "ß_"] {
+            assert_eq!(styled(&inline(text)), vec![(text.to_string(), Style::default())], "{}", text);
+        }
+        assert_eq!(plain(&render("- 日本語 🎉", 80)), vec!["• 日本語 🎉"]);
+    }
+
+    #[test]
+    fn markup_around_non_ascii_text() {
+        assert_eq!(styled(&inline("**grüß** *🎉* _日本_ `ß`")), vec![
+            ("grüß".to_string(), bold()),
+            (" ".to_string(), Style::default()),
+            ("🎉".to_string(), italic()),
+            (" ".to_string(), Style::default()),
+            ("日本".to_string(), italic()),
+            (" ".to_string(), Style::default()),
+            ("ß".to_string(), code_style()),
+        ]);
+        assert_eq!(styled(&inline("[ünï](https://ü.de)")), vec![
+            ("ünï".to_string(), link_style()),
+            (" (https://ü.de)".to_string(), Style::default().fg(Color::DarkGray)),
+        ]);
+    }
+
+    #[test]
+    fn links() {
+        assert_eq!(styled(&inline("see [docs](https://docs.rs) or https://crates.io.")), vec![
+            ("see ".to_string(), Style::default()),
+            ("docs".to_string(), link_style()),
+            (" (https://docs.rs)".to_string(), Style::default().fg(Color::DarkGray)),
+            (" or ".to_string(), Style::default()),
+            ("https://crates.io".to_string(), link_style()),
+            (".".to_string(), Style::default()),
+        ]);
+    }
+
+    #[test]
+    fn bullet_lists() {
+        assert_eq!(plain(&render("list:\n- one\n  * nested\n+ three", 80)), vec![
+            "list:", "• one", "  • nested", "• three",
+        ]);
+        assert_eq!(plain(&render("*not a bullet*", 80)), vec!["not a bullet"]);
+    }
+
+    #[test]
+    fn wrapping_keeps_styles_and_indents_list_items() {
+        let lines = render("- **bold words** wrap", 8);
+        assert_eq!(plain(&lines), vec!["• bold ", "  words ", "  wrap"]);
+        assert_eq!(lines[1].0[1].style, bold());
+    }
+
+    #[test]
+    fn long_words_are_broken() {
+        assert_eq!(plain(&render("abcdefgh", 3)), vec!["abc", "def", "gh"]);
+    }
+}
@@ -0,0 +1,180 @@
+use std::io::{Read, Write};
+use sha2::Digest;
+
+/// Bytes read from the file for a single chunk
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// Suffix of a download until the checksum is verified
+const PART_SUFFIX: &str = ".part";
+
+/// A file offered to `user`, sent by its own thread once accepted
+pub struct Upload {
+    /// Short number to refer to the transfer in commands
+    pub number: usize,
+    pub user: String,
+    pub path: std::path::PathBuf,
+    pub name: String,
+    pub size: u64,
+    pub sent: u64,
+}
+
+/// A file offered by `user`. Until it is accepted there is no file on disk.
+pub struct Download {
+    pub number: usize,
+    pub user: String,
+    pub name: String,
+    pub size: u64,
+    pub checksum: String,
+    pub received: u64,
+    /// The partial file with its path and the path it is moved to when complete
+    file: Option<(std::fs::File, std::path::PathBuf, std::path::PathBuf)>,
+    hasher: sha2::Sha256,
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Size and hex encoded sha256 of the file
+pub fn checksum(path: &std::path::Path) -> Result<(u64, String), std::io::Error> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = sha2::Sha256::new();
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut size = 0;
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+        size += n as u64;
+    }
+    Ok((size, hex(&hasher.finalize())))
+}
+
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    match unit {
+        0 => format!("{} {}", bytes, UNITS[0]),
+        _ => format!("{:.1} {}", size, UNITS[unit]),
+    }
+}
+
+pub fn percent(done: u64, size: u64) -> u64 {
+    match size {
+        0 => 100,
+        _ => done * 100 / size,
+    }
+}
+
+/// The name a sender chose, without directories that could point outside the downloads directory
+fn safe_name(name: &str) -> String {
+    match std::path::Path::new(name).file_name().and_then(|name| name.to_str()) {
+        Some(name) if !name.starts_with('.') => name.to_string(),
+        _ => "download".to_string(),
+    }
+}
+
+/// First path in the directory that does not exist yet, "name (1).ext" and so on
+fn free_path(directory: &std::path::Path, name: &str) -> std::path::PathBuf {
+    let path = std::path::Path::new(name);
+    let stem = path.file_stem().and_then(|stem| stem.to_str()).unwrap_or(name);
+    let extension = path.extension().and_then(|extension| extension.to_str());
+    let part_exists = |candidate: &std::path::Path| {
+        let mut part_name = candidate.file_name().unwrap_or_default().to_os_string();
+        part_name.push(PART_SUFFIX);
+        candidate.with_file_name(part_name).exists()
+    };
+    let mut candidate = directory.join(name);
+    let mut index = 1;
+    while candidate.exists() || part_exists(&candidate) {
+        let numbered = match extension {
+            Some(extension) => format!("{} ({}).{}", stem, index, extension),
+            None => format!("{} ({})", stem, index),
+        };
+        candidate = directory.join(numbered);
+        index += 1;
+    }
+    candidate
+}
+
+impl Download {
+    pub fn new(number: usize, user: String, name: &str, size: u64, checksum: String) -> Download {
+        Download {
+            number,
+            user,
+            name: safe_name(name),
+            size,
+            checksum,
+            received: 0,
+            file: None,
+            hasher: sha2::Sha256::new(),
+        }
+    }
+
+    pub fn is_accepted(&self) -> bool {
+        self.file.is_some()
+    }
+
+    /// Creates the partial file in the directory, the chunks are written to it
+    pub fn accept(&mut self, directory: &std::path::Path) -> Result<(), std::io::Error> {
+        std::fs::create_dir_all(directory)?;
+        let path = free_path(directory, &self.name);
+        let mut part_name = path.file_name().unwrap_or_default().to_os_string();
+        part_name.push(PART_SUFFIX);
+        let part_path = path.with_file_name(part_name);
+        let file = std::fs::OpenOptions::new().write(true).create_new(true).open(&part_path)?;
+        self.file = Some((file, part_path, path));
+        Ok(())
+    }
+
+    /// Appends the chunk and returns true once the whole file arrived
+    pub fn write(&mut self, offset: u64, data: &[u8]) -> Result<bool, std::io::Error> {
+        let (file, _, _) = match self.file.as_mut() {
+            Some(file) => file,
+            None => return Err(std::io::Error::other("The file was not accepted")),
+        };
+        if offset != self.received || self.received + data.len() as u64 > self.size {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "The chunk does not fit the offer"));
+        }
+        file.write_all(data)?;
+        self.hasher.update(data);
+        self.received += data.len() as u64;
+        Ok(self.received == self.size)
+    }
+
+    /// Verifies the checksum and moves the file to its final name
+    pub fn finish(mut self) -> Result<std::path::PathBuf, std::io::Error> {
+        let (mut file, part_path, path) = match self.file.take() {
+            Some(file) => file,
+            None => return Err(std::io::Error::other("The file was not accepted")),
+        };
+        file.flush()?;
+        drop(file);
+
+        let checksum = hex(&self.hasher.finalize());
+        if checksum != self.checksum {
+            if let Err(e) = std::fs::remove_file(&part_path) {
+                log::error!("Could not remove {:?} {:?}", part_path, e);
+            }
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "The checksum does not match, the file was deleted"));
+        }
+        std::fs::rename(&part_path, &path)?;
+        Ok(path)
+    }
+
+    /// Removes what was received so far
+    pub fn abort(mut self) {
+        if let Some((file, part_path, _)) = self.file.take() {
+            drop(file);
+            if let Err(e) = std::fs::remove_file(&part_path) {
+                log::error!("Could not remove {:?} {:?}", part_path, e);
+            }
+        }
+    }
+}
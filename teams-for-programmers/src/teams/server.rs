@@ -1,192 +1,1129 @@
-use std::collections::HashMap;
-use std::net::TcpStream;
-use std::sync::mpsc::Sender;
-use std::sync::{Arc, Mutex};
-use std::thread::{JoinHandle};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Write;
+use std::sync::mpsc::{Sender, SyncSender};
+use std::sync::Arc;
+use mio::{Interest, Token};
+
+mod accounts;
+mod login_limits;
+mod storage;
+
+/// Upper bound for the page size of a history request
+const MAX_HISTORY_PAGE: usize = 100;
+/// Per user, the oldest queued messages are dropped beyond this
+const MAX_OFFLINE_QUEUE: usize = 1000;
+/// Upper bound for the data of a single file chunk
+const MAX_FILE_CHUNK: usize = 256 * 1024;
+/// A client that does not read its messages is disconnected once this many bytes wait for it
+const MAX_WRITE_QUEUE: usize = 4 * super::MAX_FRAME_LEN;
+/// The sender of a file waits while this many bytes are queued for the recipient
+const MAX_FILE_BACKLOG: usize = 4 * MAX_FILE_CHUNK;
+/// Frames and reads of one client before the others get their turn
+const MAX_FRAMES_PER_TURN: usize = 64;
+/// How often the heartbeats of the clients are checked
+const HEARTBEAT_CHECK: std::time::Duration = std::time::Duration::from_secs(1);
+/// Threads that hash and verify passwords, every check needs about 19 MiB for Argon2
+const PASSWORD_WORKERS: usize = 2;
+/// Password checks waiting for a worker, logins beyond that are rejected until there is room again
+const MAX_WAITING_CHECKS: usize = 16;
+/// Failed logins on one connection before it is closed
+const MAX_LOGIN_ATTEMPTS: usize = 5;
+/// Time a new connection has for the tls handshake and the login
+const LOGIN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const FIRST_CLIENT: usize = 2;
+
+/// Logged in users and the token of their connection
+type HandlerMap = HashMap<String, Token>;
+/// Channel name to the usernames of its members
+type ChannelMap = HashMap<String, HashSet<String>>;
+/// Every user that entered once has an entry, holding the messages that arrived while being offline
+type OfflineQueue = HashMap<String, VecDeque<super::TeamsMessage>>;
+/// Running file transfers by their id
+type TransferMap = HashMap<u64, Transfer>;
+
+/// A file offered by `sender`. The chunks are only forwarded after the recipient accepted it.
+struct Transfer {
+    sender: String,
+    recipient: String,
+    size: u64,
+    forwarded: u64,
+    accepted: bool,
+}
+
+/// A connection of the event loop. Frames for it wait in `outgoing` until the socket takes them,
+/// so a client that reads slowly only delays itself.
+struct Client {
+    connection: super::Connection,
+    address: std::net::IpAddr,
+    outgoing: VecDeque<u8>,
+    /// None until the login succeeded
+    user: Option<String>,
+    /// The password is checked by a worker thread, the frames after the login wait for it
+    logging_in: bool,
+    failed_logins: usize,
+    connected: std::time::Instant,
+    /// The client sends a file faster than the recipient on this connection reads it
+    waiting_for: Option<Token>,
+    /// The connection failed, it is removed after the current event
+    closed: bool,
+    /// When the client sent anything last, a half-open connection stops this
+    last_seen: std::time::Instant,
+    last_ping: std::time::Instant,
+}
+
+/// Everything the event loop works on
+struct Server {
+    clients: HashMap<Token, Client>,
+    handler_map: HandlerMap,
+    channels: ChannelMap,
+    offline: OfflineQueue,
+    accounts: accounts::Accounts,
+    storage: storage::Storage,
+    transfers: TransferMap,
+    max_file_size: u64,
+    heartbeat: crate::config::Heartbeat,
+    /// Hands the password checks to the workers
+    password_checks: SyncSender<(Token, accounts::PasswordCheck)>,
+    login_limits: login_limits::LoginLimits,
+    /// Password checks that wait after too many failed logins, with the time they may start
+    delayed_checks: Vec<(std::time::Instant, Token, accounts::PasswordCheck)>,
+    /// Clients with data left after their turn, the socket does not tell about it again
+    ready: Vec<Token>,
+}
+
+impl Server {
+    /// The connection of a logged in user, None if the user is offline or the connection failed
+    fn client(&mut self, user: &str) -> Option<&mut Client> {
+        self.handler_map.get(user)
+            .and_then(|token| self.clients.get_mut(token))
+            .filter(|client| !client.closed)
+    }
+}
+
+enum Delivery {
+    Sent,
+    Queued,
+    UnknownUser,
+}
+
+/// Writes as much of the queue as the socket takes, the rest waits until it is writable again
+fn flush(client: &mut Client) {
+    while !client.outgoing.is_empty() {
+        match client.connection.stream.write(client.outgoing.as_slices().0) {
+            Ok(0) => {
+                log::error!("Could not write, disconnect");
+                client.closed = true;
+                return;
+            },
+            Ok(n) => {
+                client.outgoing.drain(..n);
+            },
+            Err(e) => match e.kind() {
+                std::io::ErrorKind::WouldBlock => return,
+                std::io::ErrorKind::Interrupted => {},
+                _ => {
+                    log::error!("Could not send, disconnect {:?}", e);
+                    client.closed = true;
+                    return;
+                },
+            },
+        }
+    }
+    // Tls keeps the last records until they are flushed
+    match client.connection.stream.flush() {
+        Err(e) if e.kind() != std::io::ErrorKind::WouldBlock => {
+            log::error!("Could not send, disconnect {:?}", e);
+            client.closed = true;
+        },
+        _ => {},
+    }
+}
+
+/// Adds the message to the write queue of the client and writes what the socket takes right away
+fn queue(client: &mut Client, message: &super::TeamsMessage) {
+    if client.closed {
+        return;
+    }
+    match super::frame(message) {
+        Ok(frame) => client.outgoing.extend(frame),
+        Err(e) => {
+            log::error!("Could not send {:?}", e);
+            return;
+        },
+    }
+    if client.outgoing.len() > MAX_WRITE_QUEUE {
+        log::warn!("Client does not read its messages, disconnect");
+        client.closed = true;
+        return;
+    }
+    flush(client);
+}
+
+/// Sends the message to a connected user, a failing connection is cleaned up after the current event
+fn send_to(server: &mut Server, recipient: &str, message: &super::TeamsMessage) {
+    if let Some(client) = server.client(recipient) {
+        queue(client, message);
+    }
+}
+
+/// Sends the message to the recipient, or queues it if the recipient is known but not connected
+fn deliver(server: &mut Server, recipient: &str, message: &super::TeamsMessage) -> Delivery {
+    if let Some(client) = server.client(recipient) {
+        queue(client, message);
+        return Delivery::Sent;
+    }
+
+    match server.offline.get_mut(recipient) {
+        Some(queue) => {
+            if queue.len() >= MAX_OFFLINE_QUEUE {
+                log::warn!("Offline queue of {} is full, drop the oldest message", recipient);
+                queue.pop_front();
+            }
+            queue.push_back(message.clone());
+            Delivery::Queued
+        },
+        None => Delivery::UnknownUser,
+    }
+}
 
 enum MainThreadMessageType {
-    Stream(TcpStream),
+    /// The password of the login on this connection was checked
+    Login(Token, accounts::CheckedPassword),
     CtrlC(()),
 }
 
-fn handle_connection(stream: TcpStream, handler_map: Arc<Mutex<HashMap<String, TcpStream>>>) {
-    let user: String;
+/// Sends the message to every connected user except `except`
+fn broadcast(server: &mut Server, except: &str, message: &super::TeamsMessage) {
+    for (_, token) in server.handler_map.iter().filter(|(user, _)| user.as_str() != except) {
+        if let Some(client) = server.clients.get_mut(token) {
+            queue(client, message);
+        }
+    }
+}
+
+/// Code has to contain a fenced block and images may only be ascii art, so clients can rely on the kind when rendering
+fn checked_kind(kind: super::MessageKind, message: &str) -> super::MessageKind {
+    match kind {
+        super::MessageKind::Code if !super::contains_code(message) => {
+            log::warn!("Message marked as code without a code block, store it as text");
+            super::MessageKind::Text
+        },
+        super::MessageKind::Image if !message.chars().all(|c| c == '\n' || c.is_ascii_graphic() || c == ' ') => {
+            log::warn!("Message marked as image is no ascii art, store it as text");
+            super::MessageKind::Text
+        },
+        kind => kind,
+    }
+}
+
+/// The thread a reply goes to, None if the message is no reply
+fn thread_root(storage: &storage::Storage, user: &str, conversation: &super::Conversation, parent: Option<u64>) -> Result<Option<u64>, String> {
+    match parent {
+        Some(parent) => storage.thread_root(user, conversation, parent).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn is_valid_channel_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= 32 && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_')
+}
 
-    let deserialized_message = super::recv(&stream);
+fn channel_list(user: &str, channels: &HashMap<String, HashSet<String>>) -> super::TeamsMessage {
+    let mut list: Vec<super::ChannelInfo> = channels.iter()
+        .map(|(name, members)| super::ChannelInfo {
+            name: name.clone(),
+            members: members.len(),
+            joined: members.contains(user),
+        })
+        .collect();
+    list.sort_by(|a, b| a.name.cmp(&b.name));
+    super::TeamsMessage::ChannelList(list)
+}
 
-    match deserialized_message {
-        Ok(request) => match request {
-            Some(request) => match request {
-                super::TeamsMessage::NewUser(username) => {
-                    log::info!("new user with username {}", username);
-                    user = username;
-                    let mut locked_map = handler_map.lock().unwrap();
-                    if locked_map.contains_key(&user) {
-                        // TODO!
-                        log::info!("Username already exists, should tell the client");
-                        return;
+fn handle_channel_request(user: &str, request: super::TeamsMessage, server: &mut Server) {
+    let result = match request {
+        super::TeamsMessage::CreateChannel(name) => {
+            if !is_valid_channel_name(&name) {
+                Err(format!("{} is not a valid channel name", name))
+            } else {
+                match server.channels.entry(name) {
+                    std::collections::hash_map::Entry::Occupied(entry) => Err(format!("Channel #{} already exists", entry.key())),
+                    std::collections::hash_map::Entry::Vacant(entry) => {
+                        log::info!("{} creates channel {}", user, entry.key());
+                        entry.insert(HashSet::from([user.to_string()]));
+                        Ok(())
+                    },
+                }
+            }
+        },
+        super::TeamsMessage::JoinChannel(name) => match server.channels.get_mut(&name) {
+            Some(members) => {
+                members.insert(user.to_string());
+                Ok(())
+            },
+            None => Err(format!("Channel #{} does not exist", name)),
+        },
+        super::TeamsMessage::LeaveChannel(name) => {
+            let removed = server.channels.get_mut(&name).map(|members| members.remove(user)).unwrap_or(false);
+            if removed {
+                Ok(())
+            } else {
+                Err(format!("You are not a member of #{}", name))
+            }
+        },
+        super::TeamsMessage::ListChannels => Ok(()),
+        super::TeamsMessage::ChannelMessage(m) => {
+            let is_member = server.channels.get(&m.channel).map(|members| members.contains(user));
+            let result = match is_member {
+                Some(true) => {
+                    let conversation = super::Conversation::Channel(m.channel.clone());
+                    match (server.storage.find_resent(user, m.local_id), thread_root(&server.storage, user, &conversation, m.parent)) {
+                        (Some((id, timestamp)), _) => {
+                            log::info!("{} sent message {} again, only acknowledge it", user, id);
+                            super::SendResult::Stored { id, timestamp }
+                        },
+                        (None, Err(reason)) => super::SendResult::Rejected(reason),
+                        (None, Ok(_)) if m.kind == super::MessageKind::Encrypted => {
+                            super::SendResult::Rejected("Only direct messages can be encrypted".to_string())
+                        },
+                        (None, Ok(parent)) => {
+                            let kind = checked_kind(m.kind, &m.message);
+                            let stored = server.storage.append(user, &conversation, &m.message, kind, m.local_id, parent);
+                            let response = super::TeamsMessage::ChannelMessage(super::ChannelMessage{
+                                channel: m.channel.clone(),
+                                user: user.to_string(),
+                                message: m.message,
+                                kind,
+                                id: stored.id,
+                                timestamp: stored.timestamp,
+                                local_id: 0,
+                                parent,
+                            });
+                            let members: Vec<String> = server.channels[&m.channel].iter()
+                                .filter(|member| member.as_str() != user)
+                                .cloned()
+                                .collect();
+                            for member in &members {
+                                deliver(server, member, &response);
+                            }
+                            super::SendResult::Stored { id: stored.id, timestamp: stored.timestamp }
+                        },
                     }
-                    let result = locked_map.insert(user.clone(), stream);
-                    assert!(result.is_none());
                 },
-                _ => {
-                    log::error!("First message must be the enter, disconnect");
+                Some(false) => super::SendResult::Rejected(format!("Join #{} before writing to it", m.channel)),
+                None => super::SendResult::Rejected(format!("Channel #{} does not exist", m.channel)),
+            };
+            let ack = super::TeamsMessage::Ack { local_id: m.local_id, result };
+            return send_to(server, user, &ack);
+        },
+        _ => unreachable!("Not a channel request"),
+    };
+
+    let response = match result {
+        Ok(()) => channel_list(user, &server.channels),
+        Err(reason) => super::TeamsMessage::Error(reason),
+    };
+    send_to(server, user, &response)
+}
+
+/// Checks the chunk against the transfer, returns why it has to be cancelled if it does not fit
+fn check_chunk(transfer: &Transfer, user: &str, chunk: &super::FileChunk) -> Result<(), String> {
+    if transfer.sender != user {
+        return Err("Only the sender may send chunks".to_string());
+    }
+    if !transfer.accepted {
+        return Err("The file was not accepted yet".to_string());
+    }
+    if chunk.offset != transfer.forwarded {
+        return Err(format!("Expected the chunk at {}, got {}", transfer.forwarded, chunk.offset));
+    }
+    if chunk.data.len() > MAX_FILE_CHUNK || transfer.forwarded + chunk.data.len() as u64 > transfer.size {
+        return Err("The file is larger than offered".to_string());
+    }
+    Ok(())
+}
+
+fn handle_file_request(user: &str, request: super::TeamsMessage, server: &mut Server) {
+    match request {
+        super::TeamsMessage::FileOffer(offer) => {
+            let error = if offer.user == user {
+                Some("You can not send files to yourself".to_string())
+            } else if offer.size > server.max_file_size {
+                Some(format!("The file is larger than the limit of {} bytes", server.max_file_size))
+            } else if !server.handler_map.contains_key(&offer.user) {
+                Some(format!("{} is not online, files can only be sent to online users", offer.user))
+            } else if server.transfers.contains_key(&offer.id) {
+                Some("The transfer id is already in use".to_string())
+            } else {
+                None
+            };
+            if let Some(reason) = error {
+                return send_to(server, user, &super::TeamsMessage::FileCancel { id: offer.id, reason });
+            }
+
+            log::info!("{} offers {} with {} bytes to {}", user, offer.name, offer.size, offer.user);
+            server.transfers.insert(offer.id, Transfer {
+                sender: user.to_string(),
+                recipient: offer.user.clone(),
+                size: offer.size,
+                forwarded: 0,
+                accepted: false,
+            });
+            let recipient = offer.user.clone();
+            send_to(server, &recipient, &super::TeamsMessage::FileOffer(super::FileOffer {
+                user: user.to_string(),
+                ..offer
+            }));
+        },
+        super::TeamsMessage::FileAccept(id) => match server.transfers.get_mut(&id) {
+            Some(transfer) if transfer.recipient == user && !transfer.accepted => {
+                transfer.accepted = true;
+                let sender = transfer.sender.clone();
+                // An empty file is complete right away
+                if transfer.size == 0 {
+                    server.transfers.remove(&id);
+                }
+                send_to(server, &sender, &super::TeamsMessage::FileAccept(id));
+            },
+            _ => send_to(server, user, &super::TeamsMessage::Error("There is no such file offer".to_string())),
+        },
+        super::TeamsMessage::FileChunk(chunk) => {
+            let transfer = match server.transfers.get_mut(&chunk.id) {
+                Some(transfer) => transfer,
+                None => {
+                    // Chunks that were already on the way when the transfer got cancelled
+                    log::warn!("Chunk of unknown transfer {} from {}, ignore", chunk.id, user);
                     return;
                 },
+            };
+            if let Err(reason) = check_chunk(transfer, user, &chunk) {
+                log::warn!("Cancel transfer {} {}", chunk.id, reason);
+                let transfer = server.transfers.remove(&chunk.id).unwrap();
+                let cancel = super::TeamsMessage::FileCancel { id: chunk.id, reason };
+                send_to(server, &transfer.recipient, &cancel);
+                send_to(server, &transfer.sender, &cancel);
+                return;
+            }
+
+            transfer.forwarded += chunk.data.len() as u64;
+            let recipient = transfer.recipient.clone();
+            if transfer.forwarded == transfer.size {
+                log::info!("Transfer {} is complete", chunk.id);
+                server.transfers.remove(&chunk.id);
+            }
+            send_to(server, &recipient, &super::TeamsMessage::FileChunk(chunk));
+
+            // Nothing else stops the sender, so its next chunks wait until the recipient caught up
+            let backlog = server.handler_map.get(&recipient)
+                .filter(|token| server.clients.get(token).map(|client| client.outgoing.len() > MAX_FILE_BACKLOG).unwrap_or(false))
+                .copied();
+            if let (Some(backlog), Some(token)) = (backlog, server.handler_map.get(user)) {
+                if let Some(client) = server.clients.get_mut(token) {
+                    client.waiting_for = Some(backlog);
+                }
+            }
+        },
+        super::TeamsMessage::FileCancel { id, reason } => match server.transfers.get(&id) {
+            Some(transfer) if transfer.sender == user || transfer.recipient == user => {
+                let transfer = server.transfers.remove(&id).unwrap();
+                let other = if transfer.sender == user { transfer.recipient } else { transfer.sender };
+                send_to(server, &other, &super::TeamsMessage::FileCancel { id, reason });
             },
-            None => {
-                log::warn!("Message type not known, disconnect!");
+            _ => log::warn!("{} cancels unknown transfer {}, ignore", user, id),
+        },
+        _ => unreachable!("Not a file request"),
+    }
+}
+
+/// An emoji or a short word, so reactions fit below a message
+fn is_valid_reaction(emoji: &str) -> bool {
+    !emoji.is_empty() && emoji.chars().count() <= 16 && !emoji.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Edits, deletes or reacts to a stored message and passes the change on to everybody in its conversation
+fn handle_change_request(user: &str, request: super::TeamsMessage, server: &mut Server) {
+    let (id, change) = match &request {
+        super::TeamsMessage::EditMessage(id, message) => {
+            let kind = checked_kind(super::MessageKind::Code, message);
+            (*id, storage::Change::Edit(message.clone(), kind))
+        },
+        super::TeamsMessage::DeleteMessage(id) => (*id, storage::Change::Delete),
+        super::TeamsMessage::React(id, emoji) => (*id, storage::Change::React(emoji.clone())),
+        super::TeamsMessage::Unreact(id, emoji) => (*id, storage::Change::Unreact(emoji.clone())),
+        _ => unreachable!("Not a change request"),
+    };
+    let allowed = match (&request, server.storage.conversation(user, id)) {
+        (super::TeamsMessage::React(_, emoji), _) if !is_valid_reaction(emoji) => Err("A reaction is a single emoji or word".to_string()),
+        (_, Ok(super::Conversation::Channel(channel))) if !server.channels.get(&channel).map(|members| members.contains(user)).unwrap_or(false) => {
+            Err(format!("Join #{} before changing its messages", channel))
+        },
+        (_, result) => result.map(|_| ()),
+    };
+    let conversation = match allowed.and_then(|_| server.storage.change(user, id, change)) {
+        Ok(conversation) => conversation,
+        Err(reason) => return send_to(server, user, &super::TeamsMessage::Error(reason)),
+    };
+    let relayed = match request {
+        super::TeamsMessage::React(..) | super::TeamsMessage::Unreact(..) => super::TeamsMessage::Reactions { id, reactions: server.storage.reactions(id) },
+        request => request,
+    };
+
+    let recipients: Vec<String> = match &conversation {
+        super::Conversation::Direct(peer) => vec![peer.clone()],
+        super::Conversation::Channel(channel) => server.channels.get(channel)
+            .map(|members| members.iter().filter(|member| member.as_str() != user).cloned().collect())
+            .unwrap_or_default(),
+    };
+    for recipient in &recipients {
+        deliver(server, recipient, &relayed);
+    }
+    // The sender applies the change once it is confirmed like this
+    send_to(server, user, &relayed)
+}
+
+/// Relays typing events and read receipts to the other users of the conversation.
+/// Receipts are queued for offline users, typing events are only of interest right now.
+fn handle_relay_request(user: &str, request: super::TeamsMessage, server: &mut Server) {
+    let (conversation, queue) = match &request {
+        super::TeamsMessage::Typing { conversation, .. } | super::TeamsMessage::StoppedTyping { conversation, .. } => (conversation.clone(), false),
+        super::TeamsMessage::Read { conversation, .. } => (conversation.clone(), true),
+        _ => unreachable!("Not a relay request"),
+    };
+    let (recipients, seen_as): (Vec<String>, super::Conversation) = match &conversation {
+        super::Conversation::Direct(peer) => (vec![peer.clone()], super::Conversation::Direct(user.to_string())),
+        super::Conversation::Channel(channel) => match server.channels.get(channel) {
+            Some(members) if members.contains(user) => (
+                members.iter().filter(|member| member.as_str() != user).cloned().collect(),
+                conversation.clone(),
+            ),
+            _ => {
+                log::warn!("{} is no member of {}, do not relay", user, channel);
                 return;
             },
         },
-        Err(e) => {
-            log::error!("Could not read, disconnect {:?}", e);
-            return;
+    };
+    let relayed = match request {
+        super::TeamsMessage::Typing { .. } => super::TeamsMessage::Typing { user: user.to_string(), conversation: seen_as },
+        super::TeamsMessage::StoppedTyping { .. } => super::TeamsMessage::StoppedTyping { user: user.to_string(), conversation: seen_as },
+        super::TeamsMessage::Read { id, .. } => super::TeamsMessage::Read { user: user.to_string(), conversation: seen_as, id },
+        _ => unreachable!("Not a relay request"),
+    };
+
+    for recipient in recipients.iter().filter(|recipient| recipient.as_str() != user) {
+        match queue {
+            true => {
+                deliver(server, recipient, &relayed);
+            },
+            false => send_to(server, recipient, &relayed),
         }
     }
+}
 
-    loop {
-        let deserialized_message = super::recv(handler_map.lock().unwrap().get(&user).unwrap());
-
-        match deserialized_message {
-            Ok(request) => match request {
-                Some(request) => match request {
-                    super::TeamsMessage::NewUser(_) => {
-                        log::error!("Already received, disconnect");
-                        break;
-                    },
-                    super::TeamsMessage::UserExit(username) => {
-                        log::info!("User leaves teams. Bye bye {}", username);
-                        break;
-                    },
-                    super::TeamsMessage::Message(m) => {
-                        log::info!("New message for user {} with message {}", m.user, &m.message);
-                        if m.user == user {
-                            log::info!("Wants to send to the same user, continue...");
-                            continue;
-                        }
-                        /*let mut locked_map = handler_map.lock().unwrap();
-                        match locked_map.get_mut(&m.user) {
-                            Some(stream) => {
-                                let response = super::TeamsMessage::Message(super::Message{user: user.clone(), message: m.message});
-                                if let Err(e) = super::send(&response, stream) {
-                                    log::error!("Could not send, disconnect {:?}", e);
-                                    break;
-                                }
-                            },
-                            None => {
-                                // TODO!
-                                log::info!("User does not exist, should probably inform the client...");
-                                continue;
-                            },
-                        }*/
-                        let mut locked_map = handler_map.lock().unwrap();
-                        let cool = locked_map.get_mut(&user).unwrap();
-                        let response = super::TeamsMessage::Message(super::Message{user: user.clone(), message: m.message});
-                        if let Err(e) = super::send(&response, cool) {
-                            log::error!("Could not send, disconnect {:?}", e);
-                            break;
+fn close(server: &mut Server, token: Token) {
+    if let Some(client) = server.clients.get_mut(&token) {
+        client.closed = true;
+    }
+}
+
+/// Handles a request of the logged in `user` on the connection `token`
+fn handle_request(user: &str, token: Token, request: super::TeamsMessage, server: &mut Server) {
+    match request {
+        super::TeamsMessage::Login(_) => {
+            log::error!("Already logged in, disconnect");
+            close(server, token);
+        },
+        super::TeamsMessage::UserExit(username) => {
+            log::info!("User leaves teams. Bye bye {}", username);
+            close(server, token);
+        },
+        super::TeamsMessage::ChangeNick(new) => {
+            // A name that logged in once keeps its offline queue, so it stays taken
+            let error = if server.offline.contains_key(&new) {
+                Some(format!("The username {} is already taken", new))
+            } else {
+                server.accounts.rename(user, &new).err()
+            };
+            if let Some(error) = error {
+                return send_to(server, user, &super::TeamsMessage::Error(error));
+            }
+
+            log::info!("{} is now known as {}", user, new);
+            server.storage.rename(user, &new);
+            server.handler_map.remove(user);
+            server.handler_map.insert(new.clone(), token);
+            if let Some(client) = server.clients.get_mut(&token) {
+                client.user = Some(new.clone());
+            }
+            let queue = server.offline.remove(user).unwrap_or_default();
+            server.offline.insert(new.clone(), queue);
+            for members in server.channels.values_mut() {
+                if members.remove(user) {
+                    members.insert(new.clone());
+                }
+            }
+            for transfer in server.transfers.values_mut() {
+                if transfer.sender == user {
+                    transfer.sender = new.clone();
+                }
+                if transfer.recipient == user {
+                    transfer.recipient = new.clone();
+                }
+            }
+            broadcast(server, "", &super::TeamsMessage::UserRenamed { old: user.to_string(), new });
+        },
+        super::TeamsMessage::Ping => send_to(server, user, &super::TeamsMessage::Pong),
+        // Every frame counts as a sign of life, nothing else to do
+        super::TeamsMessage::Pong => {},
+        super::TeamsMessage::ListUsers => {
+            let mut users: Vec<String> = server.handler_map.keys().cloned().collect();
+            users.sort();
+            send_to(server, user, &super::TeamsMessage::UserList(users));
+        },
+        super::TeamsMessage::Message(m) => {
+            log::info!("New message for user {} with message {}", m.user, &m.message);
+            let mut replies = vec![];
+            // Every user that entered once has an offline queue
+            let result = if m.user == user {
+                log::info!("Wants to send to the same user, reject");
+                super::SendResult::Rejected("You can not send messages to yourself".to_string())
+            } else if let Some((id, timestamp)) = server.storage.find_resent(user, m.local_id) {
+                log::info!("{} sent message {} again, only acknowledge it", user, id);
+                super::SendResult::Stored { id, timestamp }
+            } else if !server.offline.contains_key(&m.user) {
+                log::info!("User {} is not known, inform the client", m.user);
+                super::SendResult::Rejected(format!("{} never entered teams, the message was not delivered", m.user))
+            } else {
+                let conversation = super::Conversation::Direct(m.user.clone());
+                match thread_root(&server.storage, user, &conversation, m.parent) {
+                    Ok(parent) => {
+                        let kind = checked_kind(m.kind, &m.message);
+                        let stored = server.storage.append(user, &conversation, &m.message, kind, m.local_id, parent);
+                        let response = super::TeamsMessage::Message(super::Message{
+                            user: user.to_string(),
+                            message: m.message,
+                            kind,
+                            id: stored.id,
+                            timestamp: stored.timestamp,
+                            local_id: 0,
+                            parent,
+                        });
+                        if let Delivery::Queued | Delivery::UnknownUser = deliver(server, &m.user, &response) {
+                            log::info!("User {} is offline, queue the message", m.user);
+                            replies.push(super::TeamsMessage::UserOffline(m.user.clone()));
                         }
+                        super::SendResult::Stored { id: stored.id, timestamp: stored.timestamp }
                     },
-                },
-                None => {
-                    log::warn!("Message type not known!");
-                    continue;
+                    Err(reason) => super::SendResult::Rejected(reason),
                 }
+            };
+            replies.insert(0, super::TeamsMessage::Ack { local_id: m.local_id, result });
+            for reply in &replies {
+                send_to(server, user, reply);
+            }
+        },
+        super::TeamsMessage::HistoryRequest { conversation, before, limit, thread } => {
+            let is_member = match &conversation {
+                super::Conversation::Direct(_) => true,
+                super::Conversation::Channel(channel) => server.channels
+                    .get(channel)
+                    .map(|members| members.contains(user))
+                    .unwrap_or(false),
+            };
+            let response = if is_member {
+                let entries = server.storage.history(user, &conversation, before, limit.min(MAX_HISTORY_PAGE), thread);
+                super::TeamsMessage::History { conversation, entries, thread }
+            } else {
+                super::TeamsMessage::Error("Join the channel to read its history".to_string())
+            };
+            send_to(server, user, &response);
+        },
+        super::TeamsMessage::PublishKey(key) => {
+            if let Err(error) = server.accounts.set_public_key(user, key) {
+                send_to(server, user, &super::TeamsMessage::Error(error));
+            }
+        },
+        super::TeamsMessage::KeyRequest(other) => {
+            let key = server.accounts.public_key(&other);
+            send_to(server, user, &super::TeamsMessage::PublicKey { user: other, key });
+        },
+        request @ (super::TeamsMessage::CreateChannel(_)
+            | super::TeamsMessage::JoinChannel(_)
+            | super::TeamsMessage::LeaveChannel(_)
+            | super::TeamsMessage::ListChannels
+            | super::TeamsMessage::ChannelMessage(_)) => handle_channel_request(user, request, server),
+        request @ (super::TeamsMessage::EditMessage(..)
+            | super::TeamsMessage::DeleteMessage(_)
+            | super::TeamsMessage::React(..)
+            | super::TeamsMessage::Unreact(..)) => handle_change_request(user, request, server),
+        request @ (super::TeamsMessage::Typing { .. }
+            | super::TeamsMessage::StoppedTyping { .. }
+            | super::TeamsMessage::Read { .. }) => handle_relay_request(user, request, server),
+        request @ (super::TeamsMessage::FileOffer(_)
+            | super::TeamsMessage::FileAccept(_)
+            | super::TeamsMessage::FileChunk(_)
+            | super::TeamsMessage::FileCancel { .. }) => handle_file_request(user, request, server),
+        _ => log::warn!("Only the server sends this message type, ignore"),
+    }
+}
+
+/// Starts the login. Hashing a password takes a while, so it runs on its own thread meanwhile.
+fn handle_login(token: Token, credentials: super::Credentials, server: &mut Server) {
+    let address = match server.clients.get(&token) {
+        Some(client) => client.address,
+        None => return,
+    };
+    // Only passwords can be guessed, a session token is too long for that
+    let now = std::time::Instant::now();
+    let start = match &credentials {
+        super::Credentials::Password { user, .. } => server.login_limits.next_check(address, user, now),
+        _ => now,
+    };
+
+    match server.accounts.login(&credentials) {
+        Ok(accounts::Login::Accepted(username, session)) => enter(token, Ok((username, session)), server),
+        Ok(accounts::Login::Check(check)) if start > now => {
+            log::warn!("Many failed logins from {}, delay the password check", address);
+            if let Some(client) = server.clients.get_mut(&token) {
+                client.logging_in = true;
+            }
+            server.delayed_checks.push((start, token, check));
+        },
+        Ok(accounts::Login::Check(check)) => start_password_check(token, check, server),
+        Err(reason) => {
+            enter(token, Err(reason), server);
+            count_failed_login(token, None, server);
+        },
+    }
+}
+
+/// Hands the password check to the workers, the client waits for it without reading further frames
+fn start_password_check(token: Token, check: accounts::PasswordCheck, server: &mut Server) {
+    let rejected = match server.password_checks.try_send((token, check)) {
+        Ok(()) => None,
+        Err(std::sync::mpsc::TrySendError::Full(_)) => {
+            log::warn!("Too many password checks waiting, reject the login");
+            Some("The server is busy, try again in a moment")
+        },
+        Err(std::sync::mpsc::TrySendError::Disconnected(_)) => {
+            log::error!("The password workers stopped, reject the login");
+            Some("Logging in with a password is not possible right now")
+        },
+    };
+    if let Some(client) = server.clients.get_mut(&token) {
+        client.logging_in = rejected.is_none();
+    }
+    if let Some(reason) = rejected {
+        enter(token, Err(reason.to_string()), server);
+    }
+}
+
+/// Starts the delayed password checks whose time has come, of the clients that are still there
+fn start_delayed_checks(server: &mut Server) {
+    let now = std::time::Instant::now();
+    let (due, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut server.delayed_checks).into_iter()
+        .partition(|(start, _, _)| *start <= now);
+    server.delayed_checks = waiting;
+    for (_, token, check) in due {
+        if server.clients.get(&token).map(|client| !client.closed).unwrap_or(false) {
+            start_password_check(token, check, server);
+        }
+    }
+}
+
+/// Closes connections that keep failing to log in. The failed passwords for a user slow down the next checks for them.
+fn count_failed_login(token: Token, user: Option<&str>, server: &mut Server) {
+    let client = match server.clients.get_mut(&token) {
+        Some(client) => client,
+        None => return,
+    };
+    client.failed_logins += 1;
+    if client.failed_logins >= MAX_LOGIN_ATTEMPTS {
+        log::warn!("{} failed logins from {}, disconnect", client.failed_logins, client.address);
+        client.closed = true;
+    }
+    if let Some(user) = user {
+        server.login_limits.count_failure(client.address, user, std::time::Instant::now());
+    }
+}
+
+/// Answers the login. The client may try again as long as it is rejected.
+fn enter(token: Token, login: Result<(String, String), String>, server: &mut Server) {
+    let (username, reply) = match login {
+        Ok((username, _)) if server.handler_map.contains_key(&username) => {
+            log::info!("{} is already logged in, tell the client", username);
+            let reply = super::LoginReply::Rejected(format!("{} is already logged in", username));
+            (username, reply)
+        },
+        Ok((username, token)) => {
+            log::info!("{} logged in", username);
+            (username, super::LoginReply::Accepted(token))
+        },
+        Err(reason) => {
+            log::info!("Login rejected: {}", reason);
+            (String::new(), super::LoginReply::Rejected(reason))
+        },
+    };
+    let client = match server.clients.get_mut(&token) {
+        Some(client) => client,
+        None => {
+            log::info!("{:?} left during the login", token);
+            return;
+        },
+    };
+    queue(client, &super::TeamsMessage::LoginReply(reply.clone()));
+
+    if let super::LoginReply::Accepted(_) = reply {
+        // The queued messages go first, everything new is queued behind them
+        let queued = server.offline.entry(username.clone()).or_default().split_off(0);
+        log::info!("Deliver {} queued messages to {}", queued.len(), username);
+        for message in &queued {
+            queue(client, message);
+        }
+        client.user = Some(username.clone());
+        broadcast(server, &username, &super::TeamsMessage::NewUser(username.clone()));
+        server.handler_map.insert(username, token);
+    }
+}
+
+/// Handles the frames the client sent, until its socket is empty or its turn is over
+fn read_client(token: Token, server: &mut Server) {
+    for _ in 0..MAX_FRAMES_PER_TURN {
+        let client = match server.clients.get_mut(&token) {
+            Some(client) if !client.closed && !client.logging_in && client.waiting_for.is_none() => client,
+            _ => return,
+        };
+        let frame = match super::next_frame(&mut client.connection) {
+            Ok(Some(frame)) => frame,
+            Ok(None) => match super::fill_buffer(&mut client.connection) {
+                Ok(true) => {
+                    client.last_seen = std::time::Instant::now();
+                    continue;
+                },
+                Ok(false) => return,
+                Err(e) => {
+                    log::error!("Could not read, disconnect {:?}", e);
+                    client.closed = true;
+                    return;
+                },
             },
             Err(e) => {
                 log::error!("Could not read, disconnect {:?}", e);
-                break;
-            }
+                client.closed = true;
+                return;
+            },
+        };
+
+        match (client.user.clone(), super::deserialize(&frame)) {
+            (None, Some(super::TeamsMessage::Login(credentials))) => handle_login(token, credentials, server),
+            (None, Some(_)) => {
+                log::error!("First message must be the login, disconnect");
+                client.closed = true;
+            },
+            (None, None) => {
+                log::warn!("Message type not known, disconnect!");
+                client.closed = true;
+            },
+            (Some(user), Some(request)) => handle_request(&user, token, request, server),
+            (Some(_), None) => log::warn!("Message type not known!"),
         }
     }
 
-    if "" == user {
-        log::error!("Leaving without entering, that's strange...");
-        return;
+    if !server.ready.contains(&token) {
+        server.ready.push(token);
     }
+}
+
+/// Removes the client. For a logged in user the others learn that it left.
+fn disconnect(token: Token, server: &mut Server) {
+    let client = match server.clients.remove(&token) {
+        Some(client) => client,
+        None => return,
+    };
+    log::info!("close connection");
+    // Senders of files to this client go on, there is nobody left to wait for
+    resume_waiting(token, server);
+    let user = match client.user {
+        Some(user) => user,
+        None => return,
+    };
 
-    if handler_map.lock().unwrap().remove_entry(&user).is_none() {
+    if server.handler_map.remove_entry(&user).is_none() {
         log::error!("Someone else deleted the entry. I thought the server plays together...");
     }
-    return;
+    broadcast(server, &user, &super::TeamsMessage::UserExit(user.clone()));
+
+    // Nobody is left to send or receive the rest of the files
+    let ids: Vec<u64> = server.transfers.iter()
+        .filter(|(_, transfer)| transfer.sender == user || transfer.recipient == user)
+        .map(|(id, _)| *id)
+        .collect();
+    for id in ids {
+        let transfer = server.transfers.remove(&id).unwrap();
+        let other = if transfer.sender == user { transfer.recipient } else { transfer.sender };
+        send_to(server, &other, &super::TeamsMessage::FileCancel { id, reason: format!("{} left", user) });
+    }
+}
+
+/// Disconnects the clients whose connection failed, which can make others fail in turn
+fn remove_closed(server: &mut Server) {
+    while let Some(token) = server.clients.iter().find(|(_, client)| client.closed).map(|(token, _)| *token) {
+        disconnect(token, server);
+    }
+}
+
+/// Lets the senders of files to this client go on, once it read most of what is queued for it
+fn resume_waiting(token: Token, server: &mut Server) {
+    if server.clients.get(&token).map(|client| client.outgoing.len() > MAX_FILE_BACKLOG).unwrap_or(false) {
+        return;
+    }
+    for (waiting, client) in server.clients.iter_mut().filter(|(_, client)| client.waiting_for == Some(token)) {
+        client.waiting_for = None;
+        server.ready.push(*waiting);
+    }
+}
+
+/// Pings the logged in clients that were quiet for a while and disconnects the ones that stopped answering,
+/// so a half-open connection does not keep its user online forever. Connections that never log in are closed as well.
+fn check_heartbeats(server: &mut Server) {
+    let now = std::time::Instant::now();
+    server.login_limits.forget_old(now);
+    let heartbeat = server.heartbeat;
+    for client in server.clients.values_mut().filter(|client| !client.closed) {
+        let user = match &client.user {
+            Some(user) => user,
+            // Nobody is pinged before the login, a connection only has a while for it
+            None => {
+                if !client.logging_in && now.duration_since(client.connected) >= LOGIN_TIMEOUT {
+                    log::warn!("{} did not log in within {:?}, disconnect", client.address, LOGIN_TIMEOUT);
+                    client.closed = true;
+                }
+                continue;
+            },
+        };
+        // A paused sender of a file is not read from, but it still has to hear from the server
+        let paused = client.waiting_for.is_some();
+        if paused {
+            client.last_seen = now;
+        }
+        let silent = now.duration_since(client.last_seen);
+        if silent >= heartbeat.timeout {
+            log::warn!("{} did not answer for {:?}, disconnect", user, silent);
+            client.closed = true;
+        } else if (paused || silent >= heartbeat.interval) && now.duration_since(client.last_ping) >= heartbeat.interval {
+            client.last_ping = now;
+            queue(client, &super::TeamsMessage::Ping);
+        }
+    }
+}
+
+/// Accepts every waiting connection, the listener only tells about new ones again
+fn accept_clients(listener: &mio::net::TcpListener, acceptor: &super::transport::Acceptor, registry: &mio::Registry, next_token: &mut usize, server: &mut Server) {
+    loop {
+        let (mut stream, address) = match listener.accept() {
+            Ok((stream, address)) => {
+                log::info!("new connection from {}!", address);
+                (stream, address.ip())
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return,
+            Err(e) => {
+                log::warn!("could not establish connection: {:?}", e);
+                return;
+            },
+        };
+        let token = Token(*next_token);
+        *next_token += 1;
+        if let Err(e) = registry.register(&mut stream, token, Interest::READABLE | Interest::WRITABLE) {
+            log::error!("Could not watch the connection {:?}", e);
+            continue;
+        }
+        let stream = match acceptor.accept(stream) {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::error!("Could not set up the connection {:?}", e);
+                continue;
+            },
+        };
+        server.clients.insert(token, Client {
+            connection: super::Connection::new(stream),
+            address,
+            outgoing: VecDeque::new(),
+            user: None,
+            logging_in: false,
+            failed_logins: 0,
+            connected: std::time::Instant::now(),
+            waiting_for: None,
+            closed: false,
+            last_seen: std::time::Instant::now(),
+            last_ping: std::time::Instant::now(),
+        });
+    }
 }
 
-fn setup_ctrlc_handler(sx: &Sender<MainThreadMessageType>) {
+fn setup_ctrlc_handler(sx: &Sender<MainThreadMessageType>, waker: &Arc<mio::Waker>) {
     log::info!("Ctrl-c setup");
 
     let s_ctrlc = sx.clone();
+    let waker = waker.clone();
     ctrlc::set_handler(move || {
         s_ctrlc.send(MainThreadMessageType::CtrlC(())).unwrap_or_else(|e| {
             log::error!("{:?}", e);
             std::process::exit(1);
-        })
+        });
+        waker.wake().unwrap_or_else(|e| {
+            log::error!("{:?}", e);
+            std::process::exit(1);
+        });
     }).expect("Could not set ctrl-c handler!");
 }
 
-fn setup_tcp_listener(sx: &Sender<MainThreadMessageType>) {
-    log::info!("Bind to port 7474");
-    let s_stream = sx.clone();
-    let listener = std::net::TcpListener::bind("127.0.0.1:7474").expect("Could not bind!");
-    std::thread::spawn(move || {
-        // NOTE: Could also do this with non-blocking mode and epoll but we can also just
-        // use the channel for this...
-        // listener.set_nonblocking(true).expect("Could not switch to non-blocking mode");
-
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    log::info!("new connection!");
-                    s_stream.send(MainThreadMessageType::Stream(stream)).expect("Could not send stream over channel!");
-                }
-                Err(e) => {
-                    if e.kind() == std::io::ErrorKind::WouldBlock {
-                        log::info!("would block, but we do not care we do not use nonblocking at this point");
-                        continue;
-                    }
-
-                    log::warn!("could not establish connection: {:?}", e);
-                }
+/// Starts the threads that check the passwords. Only a few checks wait for them,
+/// so a flood of logins can not take more memory and threads than that.
+fn setup_password_workers(sx: &Sender<MainThreadMessageType>, waker: &Arc<mio::Waker>) -> SyncSender<(Token, accounts::PasswordCheck)> {
+    let (s_check, r_check) = std::sync::mpsc::sync_channel::<(Token, accounts::PasswordCheck)>(MAX_WAITING_CHECKS);
+    let r_check = Arc::new(std::sync::Mutex::new(r_check));
+    for _ in 0..PASSWORD_WORKERS {
+        let r_check = r_check.clone();
+        let s_login = sx.clone();
+        let waker = waker.clone();
+        std::thread::spawn(move || loop {
+            // The lock is only held while waiting for the next check
+            let next = r_check.lock().unwrap().recv();
+            let (token, check) = match next {
+                Ok(next) => next,
+                Err(_) => return,
+            };
+            let checked = check.run();
+            // Fails only when the server shuts down
+            if s_login.send(MainThreadMessageType::Login(token, checked)).is_err() {
+                return;
             }
-        }
-    });
+            if let Err(e) = waker.wake() {
+                log::error!("Could not wake the event loop {:?}", e);
+            }
+        });
+    }
+    s_check
+}
+
+fn setup_tcp_listener(bind: &str) -> Result<mio::net::TcpListener, std::io::Error> {
+    log::info!("Bind to {}", bind);
+    let listener = std::net::TcpListener::bind(bind)?;
+    // The event loop waits for new connections together with the clients
+    listener.set_nonblocking(true)?;
+    Ok(mio::net::TcpListener::from_std(listener))
 }
 
-pub fn run() -> Result<(), std::io::Error> {
+/// A single thread waits for all sockets at once and only handles the ones that are ready
+pub fn run(config: crate::config::ServerConfig) -> Result<(), std::io::Error> {
     log::info!("Server setup...");
-    let mut workers: Vec<JoinHandle<()>> = vec![];
-    let mut should_shutdown = false;
     let (sx, rx) = std::sync::mpsc::channel::<MainThreadMessageType>();
+    let mut poll = mio::Poll::new()?;
+    let waker = Arc::new(mio::Waker::new(poll.registry(), WAKER)?);
 
-    let user_handler_map: Arc<Mutex<HashMap<String, TcpStream>>> = Arc::new(Mutex::new(HashMap::new()));
+    let acceptor = super::transport::Acceptor::new(config.tls_cert.as_deref(), config.tls_key.as_deref())?;
+    let accounts = accounts::Accounts::open(&config.accounts_path)?;
+    // Registered users are known even before they log in, so messages to them are queued
+    let offline: OfflineQueue = accounts.names()
+        .map(|name| (name.clone(), VecDeque::new()))
+        .collect();
+    let mut server = Server {
+        clients: HashMap::new(),
+        handler_map: HashMap::new(),
+        channels: HashMap::new(),
+        offline,
+        accounts,
+        storage: storage::Storage::open(&config.history_path)?,
+        transfers: HashMap::new(),
+        max_file_size: config.max_file_size,
+        heartbeat: config.heartbeat,
+        password_checks: setup_password_workers(&sx, &waker),
+        login_limits: login_limits::LoginLimits::default(),
+        delayed_checks: vec![],
+        ready: vec![],
+    };
+    setup_ctrlc_handler(&sx, &waker);
+    let mut listener = setup_tcp_listener(&config.bind)?;
+    poll.registry().register(&mut listener, LISTENER, Interest::READABLE)?;
 
-    setup_ctrlc_handler(&sx);
-    setup_tcp_listener(&sx);
+    let mut events = mio::Events::with_capacity(1024);
+    let mut next_token = FIRST_CLIENT;
+    let mut next_check = std::time::Instant::now() + HEARTBEAT_CHECK;
+    loop {
+        // Clients with data left do not get another event for it
+        let timeout = match server.ready.is_empty() {
+            true => server.delayed_checks.iter()
+                .map(|(start, _, _)| *start)
+                .fold(next_check, std::cmp::min)
+                .saturating_duration_since(std::time::Instant::now()),
+            false => std::time::Duration::ZERO,
+        };
+        if let Err(e) = poll.poll(&mut events, Some(timeout)) {
+            if e.kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
 
-    while !should_shutdown {
-        let stream = rx.recv().unwrap_or(MainThreadMessageType::CtrlC(()));
-        match stream {
-            MainThreadMessageType::Stream(stream) => {
-                let user_handler_map_clone = Arc::clone(&user_handler_map);
-                let join_handle = std::thread::spawn(move || {
-                    log::info!("handle connection");
-                    handle_connection(stream, user_handler_map_clone);
-                    log::info!("close connection");
-                });
-                workers.push(join_handle);
+        for event in events.iter() {
+            match event.token() {
+                LISTENER => accept_clients(&listener, &acceptor, poll.registry(), &mut next_token, &mut server),
+                WAKER => {},
+                token => {
+                    if event.is_writable() {
+                        if let Some(client) = server.clients.get_mut(&token) {
+                            flush(client);
+                        }
+                        resume_waiting(token, &mut server);
+                    }
+                    // A closed or broken connection shows up as an error while reading
+                    if event.is_readable() || event.is_read_closed() || event.is_error() {
+                        read_client(token, &mut server);
+                    }
+                },
             }
-            MainThreadMessageType::CtrlC(_) => should_shutdown = true
+            remove_closed(&mut server);
         }
-    }
 
-    log::info!("graceful shutdown. Join all handlers");
-    for worker in workers {
-        match worker.join() {
-            Err(e) => log::error!("Could not join thread: {:?}", e),
-            _ => {}
+        for token in std::mem::take(&mut server.ready) {
+            read_client(token, &mut server);
+            remove_closed(&mut server);
         }
-    }
 
-    log::info!("EXIT");
-    Ok(())
-}
\ No newline at end of file
+        start_delayed_checks(&mut server);
+        remove_closed(&mut server);
+
+        if std::time::Instant::now() >= next_check {
+            check_heartbeats(&mut server);
+            remove_closed(&mut server);
+            next_check = std::time::Instant::now() + HEARTBEAT_CHECK;
+        }
+
+        for message in rx.try_iter() {
+            match message {
+                MainThreadMessageType::Login(token, checked) => {
+                    let user = checked.user().to_string();
+                    let login = server.accounts.finish_login(checked);
+                    let failed = login.is_err();
+                    enter(token, login, &mut server);
+                    if let Some(client) = server.clients.get_mut(&token) {
+                        client.logging_in = false;
+                    }
+                    if failed {
+                        count_failed_login(token, Some(&user), &mut server);
+                    }
+                    // The frames after the login waited in the socket
+                    read_client(token, &mut server);
+                    remove_closed(&mut server);
+                },
+                MainThreadMessageType::CtrlC(_) => {
+                    log::info!("graceful shutdown. Close {} connections", server.clients.len());
+                    log::info!("EXIT");
+                    return Ok(());
+                },
+            }
+        }
+    }
+}
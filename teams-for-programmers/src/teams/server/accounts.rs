@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use rand_core::{OsRng, RngCore};
+use base64::Engine;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use super::super::Credentials;
+
+const MIN_PASSWORD_LEN: usize = 8;
+const MAX_USERNAME_LEN: usize = 32;
+const PUBLIC_KEY_LEN: usize = 32;
+/// Seconds a session token stays valid after it was last used
+const SESSION_LIFETIME: u64 = 30 * 24 * 60 * 60;
+/// Per account, the oldest sessions are dropped beyond this
+const MAX_SESSIONS: usize = 10;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Session {
+    /// Hex encoded sha256 of the token, only the client knows the token itself
+    token_hash: String,
+    /// Seconds since the unix epoch
+    expires: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Account {
+    /// Argon2 hash in the PHC string format, which includes the salt
+    password_hash: String,
+    #[serde(default)]
+    sessions: Vec<Session>,
+    /// Base64 encoded x25519 key for encrypted direct messages, the client keeps the secret one
+    #[serde(default)]
+    public_key: Option<String>,
+    /// Names the account had before. Nobody else can register them, the stored messages were sent under them.
+    #[serde(default)]
+    former_names: Vec<String>,
+}
+
+pub enum Login {
+    /// The username together with the session token to log in with next time
+    Accepted(String, String),
+    Check(PasswordCheck),
+}
+
+/// Hashing or verifying a password takes a while, so it runs without the accounts
+pub struct PasswordCheck {
+    user: String,
+    password: String,
+    /// None for a registration, the password is hashed then
+    password_hash: Option<String>,
+}
+
+/// The result of a `PasswordCheck`, holding the new hash for a registration
+pub struct CheckedPassword {
+    user: String,
+    result: Result<Option<String>, String>,
+}
+
+impl PasswordCheck {
+    pub fn run(self) -> CheckedPassword {
+        let result = match &self.password_hash {
+            None => hash_password(&self.password).map(Some),
+            Some(password_hash) if verify_password(&self.password, password_hash) => Ok(None),
+            Some(_) => Err("Wrong username or password".to_string()),
+        };
+        CheckedPassword { user: self.user, result }
+    }
+}
+
+impl CheckedPassword {
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+}
+
+/// The accounts by username. The file is a single json object that is written again after every change.
+pub struct Accounts {
+    path: std::path::PathBuf,
+    accounts: HashMap<String, Account>,
+    /// Hash of a random password that unknown users are checked against, so they take as long as known ones
+    dummy_hash: String,
+}
+
+fn is_valid_username(name: &str) -> bool {
+    !name.is_empty() && name.chars().count() <= MAX_USERNAME_LEN && !name.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn token_hash(token: &str) -> String {
+    hex(&sha2::Sha256::digest(token.as_bytes()))
+}
+
+fn hash_password(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    argon2::Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| {
+            log::error!("Could not hash the password {:?}", e);
+            "The account could not be created".to_string()
+        })
+}
+
+fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(parsed) => argon2::Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok(),
+        Err(e) => {
+            log::error!("Stored password hash is broken {:?}", e);
+            false
+        },
+    }
+}
+
+impl Accounts {
+    /// Reads the accounts, a missing file means that there are none yet.
+    /// A broken file is an error, starting without the accounts would let anybody take the names.
+    pub fn open(path: &std::path::Path) -> Result<Accounts, std::io::Error> {
+        let accounts = match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).map_err(|e| {
+                std::io::Error::new(std::io::ErrorKind::InvalidData, format!("Invalid accounts file {:?}: {}", path, e))
+            })?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e),
+        };
+        log::info!("Loaded {} accounts from {:?}", accounts.len(), path);
+        let mut random = [0u8; 32];
+        OsRng.fill_bytes(&mut random);
+        let dummy_hash = hash_password(&hex(&random)).map_err(std::io::Error::other)?;
+        Ok(Accounts { path: path.to_path_buf(), accounts, dummy_hash })
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &String> {
+        self.accounts.keys()
+    }
+
+    /// The current name of the account that has or had the name
+    fn owner(&self, name: &str) -> Option<&String> {
+        self.accounts.iter()
+            .find(|(current, account)| current.as_str() == name || account.former_names.iter().any(|former| former == name))
+            .map(|(current, _)| current)
+    }
+
+    /// Checks the credentials. A token is checked right away, a password needs a `PasswordCheck` first.
+    pub fn login(&mut self, credentials: &Credentials) -> Result<Login, String> {
+        match credentials {
+            Credentials::Register { user, password } => {
+                if !is_valid_username(user) {
+                    return Err(format!("{:?} is not a valid username", user));
+                }
+                if self.owner(user).is_some() {
+                    return Err(format!("The username {} is already registered", user));
+                }
+                if password.chars().count() < MIN_PASSWORD_LEN {
+                    return Err(format!("The password needs at least {} characters", MIN_PASSWORD_LEN));
+                }
+                Ok(Login::Check(PasswordCheck { user: user.clone(), password: password.clone(), password_hash: None }))
+            },
+            // Without an account the check fails in finish_login, but only after taking as long as a real one
+            Credentials::Password { user, password } => Ok(Login::Check(PasswordCheck {
+                user: user.clone(),
+                password: password.clone(),
+                password_hash: Some(self.accounts.get(user).map(|account| &account.password_hash).unwrap_or(&self.dummy_hash).clone()),
+            })),
+            Credentials::Token { user, token } => {
+                let now = now();
+                let hash = token_hash(token);
+                let session = self.accounts.get_mut(user)
+                    .and_then(|account| account.sessions.iter_mut().find(|session| session.token_hash == hash && session.expires > now));
+                match session {
+                    Some(session) => {
+                        session.expires = now + SESSION_LIFETIME;
+                        self.save();
+                        Ok(Login::Accepted(user.clone(), token.clone()))
+                    },
+                    None => Err("The session expired, log in with the password".to_string()),
+                }
+            },
+        }
+    }
+
+    /// Creates the account of a registration and returns the username together with the session token to log in with next time
+    pub fn finish_login(&mut self, checked: CheckedPassword) -> Result<(String, String), String> {
+        let user = checked.user;
+        match checked.result? {
+            // Somebody else could have registered the name while the password was hashed
+            Some(_) if self.owner(&user).is_some() => return Err(format!("The username {} is already registered", user)),
+            Some(password_hash) => {
+                log::info!("Register {}", user);
+                self.accounts.insert(user.clone(), Account { password_hash, sessions: vec![], public_key: None, former_names: vec![] });
+            },
+            // An unknown user, or the account was renamed meanwhile
+            None if !self.accounts.contains_key(&user) => return Err("Wrong username or password".to_string()),
+            None => {},
+        }
+
+        let mut bytes = [0u8; 32];
+        OsRng.fill_bytes(&mut bytes);
+        let token = hex(&bytes);
+        let now = now();
+        let account = self.accounts.get_mut(&user).unwrap();
+        account.sessions.retain(|session| session.expires > now);
+        if account.sessions.len() >= MAX_SESSIONS {
+            account.sessions.remove(0);
+        }
+        account.sessions.push(Session { token_hash: token_hash(&token), expires: now + SESSION_LIFETIME });
+        self.save();
+        Ok((user, token))
+    }
+
+    /// Replaces the published key of the user, e.g. after the client lost the secret key and made a new one
+    pub fn set_public_key(&mut self, user: &str, key: String) -> Result<(), String> {
+        let decoded = base64::engine::general_purpose::STANDARD.decode(&key).map_err(|_| "The public key is no base64".to_string())?;
+        if decoded.len() != PUBLIC_KEY_LEN {
+            return Err(format!("A public key has {} bytes", PUBLIC_KEY_LEN));
+        }
+        let account = match self.accounts.get_mut(user) {
+            Some(account) => account,
+            None => return Err(format!("There is no account {}", user)),
+        };
+        if account.public_key.as_ref() != Some(&key) {
+            log::info!("{} published a new key", user);
+            account.public_key = Some(key);
+            self.save();
+        }
+        Ok(())
+    }
+
+    pub fn public_key(&self, user: &str) -> Option<String> {
+        self.accounts.get(user).and_then(|account| account.public_key.clone())
+    }
+
+    /// Moves the account to the new name, the sessions stay valid. The old name stays reserved for the account,
+    /// so it can take it back but nobody else can register it.
+    pub fn rename(&mut self, old: &str, new: &str) -> Result<(), String> {
+        if !is_valid_username(new) {
+            return Err(format!("{:?} is not a valid username", new));
+        }
+        match self.owner(new) {
+            Some(owner) if owner != old || new == old => return Err(format!("The username {} is already taken", new)),
+            _ => {},
+        }
+        if let Some(mut account) = self.accounts.remove(old) {
+            account.former_names.retain(|former| former != new);
+            account.former_names.push(old.to_string());
+            self.accounts.insert(new.to_string(), account);
+            self.save();
+        }
+        Ok(())
+    }
+
+    /// Writes a new file and moves it over the old one, so a crash can not leave half of the accounts behind.
+    /// If that fails the accounts are still kept in memory.
+    fn save(&self) {
+        let content = serde_json::to_string_pretty(&self.accounts).expect("Could not serialize the accounts!");
+        let mut temporary_name = self.path.file_name().unwrap_or_default().to_os_string();
+        temporary_name.push(".tmp");
+        let temporary = self.path.with_file_name(temporary_name);
+        if let Err(e) = std::fs::write(&temporary, content).and_then(|_| std::fs::rename(&temporary, &self.path)) {
+            log::error!("Could not write the accounts to {:?} {:?}", self.path, e);
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+
+/// Failed password logins for one user from one address before the further checks are delayed
+const MAX_FAILED_LOGINS: usize = 20;
+/// Time between the delayed password checks for one user from one address
+const FAILED_LOGIN_DELAY: std::time::Duration = std::time::Duration::from_secs(3);
+/// Failed logins are forgotten this long after the first one
+const FAILED_LOGINS_KEPT: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+/// Failed logins of one user from one address, counted from the first one
+struct Failures {
+    count: usize,
+    since: std::time::Instant,
+    /// When the next delayed check may start
+    next_check: std::time::Instant,
+}
+
+/// Slows down guessing the password of a user. Only the checks for that user from that address wait,
+/// so nobody else is locked out and the right password still gets in, only later.
+#[derive(Default)]
+pub struct LoginLimits {
+    failures: HashMap<(std::net::IpAddr, String), Failures>,
+}
+
+impl LoginLimits {
+    /// When the password check for the user may start. After too many failed logins the checks take turns.
+    pub fn next_check(&mut self, address: std::net::IpAddr, user: &str, now: std::time::Instant) -> std::time::Instant {
+        match self.failures.get_mut(&(address, user.to_string())) {
+            Some(failures) if failures.count >= MAX_FAILED_LOGINS && now.duration_since(failures.since) < FAILED_LOGINS_KEPT => {
+                let start = failures.next_check.max(now);
+                failures.next_check = start + FAILED_LOGIN_DELAY;
+                start
+            },
+            _ => now,
+        }
+    }
+
+    pub fn count_failure(&mut self, address: std::net::IpAddr, user: &str, now: std::time::Instant) {
+        let failures = self.failures.entry((address, user.to_string()))
+            .or_insert(Failures { count: 0, since: now, next_check: now });
+        if now.duration_since(failures.since) >= FAILED_LOGINS_KEPT {
+            *failures = Failures { count: 0, since: now, next_check: now };
+        }
+        failures.count += 1;
+    }
+
+    pub fn forget_old(&mut self, now: std::time::Instant) {
+        self.failures.retain(|_, failures| now.duration_since(failures.since) < FAILED_LOGINS_KEPT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(last: u8) -> std::net::IpAddr {
+        std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, last))
+    }
+
+    #[test]
+    fn checks_wait_after_too_many_failures() {
+        let mut limits = LoginLimits::default();
+        let now = std::time::Instant::now();
+        for _ in 0..MAX_FAILED_LOGINS {
+            assert_eq!(limits.next_check(address(1), "alice", now), now);
+            limits.count_failure(address(1), "alice", now);
+        }
+        // The checks are not rejected, they take turns
+        assert_eq!(limits.next_check(address(1), "alice", now), now);
+        assert_eq!(limits.next_check(address(1), "alice", now), now + FAILED_LOGIN_DELAY);
+        assert_eq!(limits.next_check(address(1), "alice", now), now + 2 * FAILED_LOGIN_DELAY);
+        let later = now + 10 * FAILED_LOGIN_DELAY;
+        assert_eq!(limits.next_check(address(1), "alice", later), later);
+    }
+
+    #[test]
+    fn only_the_guessed_user_from_the_address_waits() {
+        let mut limits = LoginLimits::default();
+        let now = std::time::Instant::now();
+        for _ in 0..=MAX_FAILED_LOGINS {
+            limits.count_failure(address(1), "alice", now);
+        }
+        limits.next_check(address(1), "alice", now);
+        assert!(limits.next_check(address(1), "alice", now) > now);
+        assert_eq!(limits.next_check(address(1), "bob", now), now);
+        assert_eq!(limits.next_check(address(2), "alice", now), now);
+    }
+
+    #[test]
+    fn failures_are_forgotten() {
+        let mut limits = LoginLimits::default();
+        let now = std::time::Instant::now();
+        for _ in 0..MAX_FAILED_LOGINS {
+            limits.count_failure(address(1), "alice", now);
+        }
+        let later = now + FAILED_LOGINS_KEPT;
+        assert_eq!(limits.next_check(address(1), "alice", later), later);
+        limits.count_failure(address(1), "alice", later);
+        assert_eq!(limits.next_check(address(1), "alice", later), later);
+        limits.forget_old(later + FAILED_LOGINS_KEPT);
+        assert!(limits.failures.is_empty());
+    }
+}
@@ -0,0 +1,549 @@
+use std::io::{BufRead, Read, Seek, Write};
+use serde::{Deserialize, Serialize};
+use super::super::{Conversation, HistoryEntry, MessageKind, Reaction};
+
+/// Only this many of the newest messages are searched for a resent message
+const MAX_RESEND_LOOKUP: usize = 10_000;
+/// Different emoji a single message can collect
+const MAX_REACTIONS: usize = 20;
+
+/// How a conversation is stored, independent of who asks for it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+enum ConversationKey {
+    /// Both usernames, sorted
+    Direct(String, String),
+    Channel(String),
+}
+
+impl ConversationKey {
+    fn new(user: &str, conversation: &Conversation) -> ConversationKey {
+        match conversation {
+            Conversation::Direct(other) => {
+                if user <= other.as_str() {
+                    ConversationKey::Direct(user.to_string(), other.clone())
+                } else {
+                    ConversationKey::Direct(other.clone(), user.to_string())
+                }
+            },
+            Conversation::Channel(channel) => ConversationKey::Channel(channel.clone()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct StoredMessage {
+    id: u64,
+    conversation: ConversationKey,
+    user: String,
+    message: String,
+    #[serde(default)]
+    kind: MessageKind,
+    timestamp: u64,
+    /// Id the sending client chose, 0 if it did not
+    #[serde(default)]
+    local_id: u64,
+    /// First message of the thread this one replies to
+    #[serde(default)]
+    parent: Option<u64>,
+    /// These follow from the records after the message, they are not part of its line
+    #[serde(skip)]
+    edited: bool,
+    #[serde(skip)]
+    deleted: bool,
+    #[serde(skip)]
+    reactions: Vec<Reaction>,
+    #[serde(skip)]
+    replies: usize,
+}
+
+/// A line of the history file
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum Record {
+    Message(StoredMessage),
+    Edit {
+        edited: u64,
+        message: String,
+        kind: MessageKind,
+    },
+    Delete {
+        deleted: u64,
+    },
+    React {
+        reacted: u64,
+        user: String,
+        emoji: String,
+    },
+    Unreact {
+        unreacted: u64,
+        user: String,
+        emoji: String,
+    },
+    /// The user changed the name, everything before belongs to the new name
+    Rename {
+        renamed: String,
+        to: String,
+    },
+}
+
+/// What a user wants to change about a stored message. Only the sender may edit or delete it.
+pub enum Change {
+    Edit(String, MessageKind),
+    Delete,
+    React(String),
+    Unreact(String),
+}
+
+/// Append only message log. Every line of the file is one json encoded Record, edits, deletions and reactions
+/// are applied to the messages in memory, which answer the history requests. Deleted messages are
+/// kept there without their text, so their ids are never given out again.
+pub struct Storage {
+    file: std::fs::File,
+    messages: Vec<StoredMessage>,
+}
+
+impl Storage {
+    pub fn open(path: &std::path::Path) -> Result<Storage, std::io::Error> {
+        let mut messages = vec![];
+        if let Ok(file) = std::fs::File::open(path) {
+            for (number, line) in std::io::BufReader::new(file).lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<Record>(&line) {
+                    Ok(Record::Message(m)) => {
+                        count_reply(&mut messages, m.parent, 1);
+                        messages.push(m);
+                    },
+                    Ok(change) => {
+                        apply(&mut messages, change);
+                    },
+                    // Most likely the server died while writing the last line
+                    Err(e) => log::error!("Could not read history line {}, skip it {:?}", number + 1, e),
+                }
+            }
+        }
+        log::info!("Loaded {} messages from {:?}", messages.len(), path);
+
+        let mut file = std::fs::OpenOptions::new().create(true).read(true).append(true).open(path)?;
+        // The next record must not continue a line that was cut off
+        if ends_in_partial_line(&mut file)? {
+            file.write_all(b"\n")?;
+        }
+        Ok(Storage { file, messages })
+    }
+
+    /// Appends the message from `user` to the log and returns it with the id and timestamp it got.
+    /// If writing the file fails the message is still kept in memory.
+    pub fn append(&mut self, user: &str, conversation: &Conversation, message: &str, kind: MessageKind, local_id: u64, parent: Option<u64>) -> HistoryEntry {
+        let id = self.messages.last().map(|m| m.id + 1).unwrap_or(1);
+        let stored = StoredMessage {
+            id,
+            conversation: ConversationKey::new(user, conversation),
+            user: user.to_string(),
+            message: message.to_string(),
+            kind,
+            timestamp: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            local_id,
+            parent,
+            edited: false,
+            deleted: false,
+            reactions: vec![],
+            replies: 0,
+        };
+
+        self.write(&Record::Message(stored.clone()));
+        count_reply(&mut self.messages, parent, 1);
+        let entry = history_entry(&stored);
+        self.messages.push(stored);
+        entry
+    }
+
+    /// The conversation of the message as `user` sees it. Fails if the message was deleted,
+    /// or if it is a direct message between two other users. Channel members are not checked here.
+    pub fn conversation(&self, user: &str, id: u64) -> Result<Conversation, String> {
+        let message = match find(&self.messages, id) {
+            Some(index) if !self.messages[index].deleted => &self.messages[index],
+            Some(_) => return Err("The message was deleted".to_string()),
+            None => return Err(format!("There is no message {}", id)),
+        };
+        match &message.conversation {
+            ConversationKey::Direct(first, second) if first == user => Ok(Conversation::Direct(second.clone())),
+            ConversationKey::Direct(first, second) if second == user => Ok(Conversation::Direct(first.clone())),
+            ConversationKey::Direct(..) => Err(format!("There is no message {}", id)),
+            ConversationKey::Channel(channel) => Ok(Conversation::Channel(channel.clone())),
+        }
+    }
+
+    /// The first message of the thread that a reply of `user` to `parent` belongs to.
+    /// Threads do not branch further, a reply to a reply goes to the same thread.
+    pub fn thread_root(&self, user: &str, conversation: &Conversation, parent: u64) -> Result<u64, String> {
+        let message = match find(&self.messages, parent) {
+            Some(index) => &self.messages[index],
+            None => return Err(format!("There is no message {} to reply to", parent)),
+        };
+        if message.conversation != ConversationKey::new(user, conversation) {
+            return Err(format!("There is no message {} to reply to", parent));
+        }
+        if message.deleted {
+            return Err("The message was deleted".to_string());
+        }
+        Ok(message.parent.unwrap_or(parent))
+    }
+
+    /// Applies the change of `user` to the message with the id and returns its conversation as `user` sees it
+    pub fn change(&mut self, user: &str, id: u64, change: Change) -> Result<Conversation, String> {
+        let conversation = self.conversation(user, id)?;
+        let message = &self.messages[find(&self.messages, id).unwrap()];
+        let message_kind = message.kind;
+        let record = match change {
+            Change::Edit(..) | Change::Delete if message.user != user => return Err("Only the sender can change a message".to_string()),
+            Change::Edit(..) if message.kind == MessageKind::Image => return Err("Images can not be edited".to_string()),
+            // The server can not look into an encrypted message, so the new text has to be encrypted as well
+            Change::Edit(message, _) if message_kind == MessageKind::Encrypted => Record::Edit { edited: id, message, kind: MessageKind::Encrypted },
+            Change::Edit(message, kind) => Record::Edit { edited: id, message, kind },
+            Change::Delete => Record::Delete { deleted: id },
+            Change::React(emoji) if message.reactions.len() >= MAX_REACTIONS
+                && !message.reactions.iter().any(|reaction| reaction.emoji == emoji) => {
+                return Err(format!("A message can have at most {} different reactions", MAX_REACTIONS));
+            },
+            Change::React(emoji) => Record::React { reacted: id, user: user.to_string(), emoji },
+            Change::Unreact(emoji) => Record::Unreact { unreacted: id, user: user.to_string(), emoji },
+        };
+        // Reacting twice changes nothing, so there is no need to write it
+        if apply(&mut self.messages, record.clone()) {
+            self.write(&record);
+        }
+        Ok(conversation)
+    }
+
+    /// Moves the messages, conversations and reactions of the user to the new name
+    pub fn rename(&mut self, old: &str, new: &str) {
+        let record = Record::Rename { renamed: old.to_string(), to: new.to_string() };
+        if apply(&mut self.messages, record.clone()) {
+            self.write(&record);
+        }
+    }
+
+    pub fn reactions(&self, id: u64) -> Vec<Reaction> {
+        find(&self.messages, id).map(|index| self.messages[index].reactions.clone()).unwrap_or_default()
+    }
+
+    /// Appends the record to the file. If that fails the change is still kept in memory.
+    fn write(&mut self, record: &Record) {
+        let mut line = serde_json::to_string(record).expect("Could not serialize history record!");
+        line.push('\n');
+        if let Err(e) = self.file.write_all(line.as_bytes()).and_then(|_| self.file.flush()) {
+            log::error!("Could not write {:?} to the history file {:?}", record, e);
+        }
+    }
+
+    /// The recently stored message of `sender` with the id its client chose, if it was sent before.
+    /// Every client counts its ids on its own, so they only identify a message together with the sender.
+    pub fn find_resent(&self, sender: &str, local_id: u64) -> Option<(u64, u64)> {
+        if local_id == 0 {
+            return None;
+        }
+        self.messages.iter()
+            .rev()
+            .take(MAX_RESEND_LOOKUP)
+            .find(|m| m.local_id == local_id && m.user == sender)
+            .map(|m| (m.id, m.timestamp))
+    }
+
+    /// Returns up to `limit` messages of the conversation older than `before`, oldest first.
+    /// These are the replies to `thread`, or the messages outside of threads if it is None.
+    pub fn history(&self, user: &str, conversation: &Conversation, before: Option<u64>, limit: usize, thread: Option<u64>) -> Vec<HistoryEntry> {
+        let key = ConversationKey::new(user, conversation);
+        let mut entries: Vec<HistoryEntry> = self.messages.iter()
+            .rev()
+            .filter(|m| before.map(|before| m.id < before).unwrap_or(true))
+            .filter(|m| m.conversation == key && m.parent == thread && !m.deleted)
+            .take(limit)
+            .map(history_entry)
+            .collect();
+        entries.reverse();
+        entries
+    }
+}
+
+fn history_entry(message: &StoredMessage) -> HistoryEntry {
+    HistoryEntry {
+        id: message.id,
+        user: message.user.clone(),
+        message: message.message.clone(),
+        kind: message.kind,
+        timestamp: message.timestamp,
+        edited: message.edited,
+        reactions: message.reactions.clone(),
+        replies: message.replies,
+    }
+}
+
+fn ends_in_partial_line(file: &mut std::fs::File) -> Result<bool, std::io::Error> {
+    if file.metadata()?.len() == 0 {
+        return Ok(false);
+    }
+    let mut last = [0u8; 1];
+    file.seek(std::io::SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] != b'\n')
+}
+
+/// Adds `delta` to the reply count of the thread the message belongs to, if it belongs to one
+fn count_reply(messages: &mut [StoredMessage], parent: Option<u64>, delta: isize) {
+    if let Some(index) = parent.and_then(|parent| find(messages, parent)) {
+        messages[index].replies = messages[index].replies.saturating_add_signed(delta);
+    }
+}
+
+/// Index of the message with the id, the ids grow with every appended message
+fn find(messages: &[StoredMessage], id: u64) -> Option<usize> {
+    messages.binary_search_by_key(&id, |m| m.id).ok()
+}
+
+/// Replaces the name everywhere, returns false if it was not used anywhere
+fn rename(messages: &mut [StoredMessage], old: &str, new: &str) -> bool {
+    let mut changed = false;
+    for message in messages.iter_mut() {
+        if message.user == old {
+            message.user = new.to_string();
+            changed = true;
+        }
+        if let ConversationKey::Direct(first, second) = &message.conversation {
+            if first == old || second == old {
+                let other = if first == old { second.clone() } else { first.clone() };
+                message.conversation = ConversationKey::new(new, &Conversation::Direct(other));
+                changed = true;
+            }
+        }
+        for reaction in message.reactions.iter_mut() {
+            for user in reaction.users.iter_mut().filter(|user| user.as_str() == old) {
+                *user = new.to_string();
+                changed = true;
+            }
+        }
+    }
+    changed
+}
+
+/// Applies a record that changes a message, returns false if it did not change anything
+fn apply(messages: &mut [StoredMessage], record: Record) -> bool {
+    let id = match &record {
+        Record::Message(_) => return false,
+        Record::Rename { renamed, to } => return rename(messages, renamed, to),
+        Record::Edit { edited: id, .. } | Record::Delete { deleted: id } => *id,
+        Record::React { reacted: id, .. } | Record::Unreact { unreacted: id, .. } => *id,
+    };
+    let message = match find(messages, id) {
+        Some(index) => &mut messages[index],
+        None => {
+            log::error!("The history changes message {} which does not exist, skip it", id);
+            return false;
+        },
+    };
+    match record {
+        Record::Message(_) | Record::Rename { .. } => false,
+        Record::Edit { message: text, kind, .. } => {
+            message.message = text;
+            message.kind = kind;
+            message.edited = true;
+            true
+        },
+        Record::Delete { .. } => {
+            message.message.clear();
+            message.reactions.clear();
+            message.deleted = true;
+            let parent = message.parent;
+            count_reply(messages, parent, -1);
+            true
+        },
+        Record::React { user, emoji, .. } => match message.reactions.iter_mut().find(|reaction| reaction.emoji == emoji) {
+            Some(reaction) if reaction.users.contains(&user) => false,
+            Some(reaction) => {
+                reaction.users.push(user);
+                true
+            },
+            None => {
+                message.reactions.push(Reaction { emoji, users: vec![user] });
+                true
+            },
+        },
+        Record::Unreact { user, emoji, .. } => {
+            let reaction = match message.reactions.iter_mut().find(|reaction| reaction.emoji == emoji) {
+                Some(reaction) if reaction.users.contains(&user) => reaction,
+                _ => return false,
+            };
+            reaction.users.retain(|u| *u != user);
+            message.reactions.retain(|reaction| !reaction.users.is_empty());
+            true
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A history file in the temp directory, removed again at the end of the test
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str) -> TempFile {
+            let path = std::env::temp_dir().join(format!("teams-storage-{}-{}.jsonl", std::process::id(), name));
+            let _ = std::fs::remove_file(&path);
+            TempFile(path)
+        }
+
+        fn open(&self) -> Storage {
+            Storage::open(&self.0).unwrap()
+        }
+
+        fn lines(&self) -> usize {
+            std::fs::read_to_string(&self.0).unwrap().lines().count()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn direct(user: &str) -> Conversation {
+        Conversation::Direct(user.to_string())
+    }
+
+    fn texts(entries: &[HistoryEntry]) -> Vec<(String, String)> {
+        entries.iter().map(|entry| (entry.user.clone(), entry.message.clone())).collect()
+    }
+
+    fn pair(user: &str, message: &str) -> (String, String) {
+        (user.to_string(), message.to_string())
+    }
+
+    #[test]
+    fn messages_survive_reopening() {
+        let file = TempFile::new("reopen");
+        let mut storage = file.open();
+        storage.append("alice", &direct("bob"), "hi bob", MessageKind::Text, 1, None);
+        storage.append("bob", &direct("alice"), "```\ncode\n```", MessageKind::Code, 1, None);
+        storage.append("carol", &Conversation::Channel("rust".to_string()), "hi all", MessageKind::Text, 1, None);
+        drop(storage);
+
+        let mut storage = file.open();
+        let history = storage.history("bob", &direct("alice"), None, 10, None);
+        assert_eq!(texts(&history), vec![pair("alice", "hi bob"), pair("bob", "```\ncode\n```")]);
+        assert_eq!(history[1].kind, MessageKind::Code);
+        assert_eq!(texts(&storage.history("alice", &direct("bob"), Some(2), 10, None)), vec![pair("alice", "hi bob")]);
+        assert!(storage.history("carol", &direct("alice"), None, 10, None).is_empty());
+        assert_eq!(storage.history("alice", &Conversation::Channel("rust".to_string()), None, 10, None).len(), 1);
+        assert_eq!(storage.append("alice", &direct("bob"), "again", MessageKind::Text, 2, None).id, 4);
+    }
+
+    #[test]
+    fn resent_messages_are_found_per_sender() {
+        let file = TempFile::new("resent");
+        let mut storage = file.open();
+        let alice = storage.append("alice", &direct("bob"), "from alice", MessageKind::Text, 7, None);
+        let bob = storage.append("bob", &direct("alice"), "from bob", MessageKind::Text, 7, None);
+        storage.append("carol", &direct("bob"), "no local id", MessageKind::Text, 0, None);
+        drop(storage);
+
+        let storage = file.open();
+        assert_eq!(storage.find_resent("alice", 7), Some((alice.id, alice.timestamp)));
+        assert_eq!(storage.find_resent("bob", 7), Some((bob.id, bob.timestamp)));
+        assert_eq!(storage.find_resent("carol", 7), None);
+        assert_eq!(storage.find_resent("carol", 0), None);
+    }
+
+    #[test]
+    fn edits_and_deletions() {
+        let file = TempFile::new("edit");
+        let mut storage = file.open();
+        let first = storage.append("alice", &direct("bob"), "first", MessageKind::Text, 1, None).id;
+        let second = storage.append("alice", &direct("bob"), "second", MessageKind::Text, 2, None).id;
+        let reply = storage.append("bob", &direct("alice"), "reply", MessageKind::Text, 1, Some(first)).id;
+
+        assert!(storage.change("bob", first, Change::Edit("mine now".to_string(), MessageKind::Text)).is_err());
+        assert!(storage.change("carol", first, Change::Delete).is_err());
+        assert_eq!(storage.change("alice", first, Change::Edit("first, edited".to_string(), MessageKind::Text)), Ok(direct("bob")));
+        assert_eq!(storage.change("alice", second, Change::Delete), Ok(direct("bob")));
+        assert_eq!(storage.change("bob", reply, Change::Delete), Ok(direct("alice")));
+        drop(storage);
+
+        let storage = file.open();
+        let history = storage.history("alice", &direct("bob"), None, 10, None);
+        assert_eq!(texts(&history), vec![pair("alice", "first, edited")]);
+        assert!(history[0].edited);
+        assert_eq!(history[0].replies, 0);
+        assert!(storage.history("alice", &direct("bob"), None, 10, Some(first)).is_empty());
+        assert_eq!(storage.conversation("alice", second), Err("The message was deleted".to_string()));
+    }
+
+    #[test]
+    fn reactions_toggle() {
+        let file = TempFile::new("react");
+        let mut storage = file.open();
+        let id = storage.append("alice", &Conversation::Channel("rust".to_string()), "ship it", MessageKind::Text, 1, None).id;
+        storage.change("bob", id, Change::React("👍".to_string())).unwrap();
+        storage.change("bob", id, Change::React("👍".to_string())).unwrap();
+        storage.change("carol", id, Change::React("👍".to_string())).unwrap();
+        storage.change("carol", id, Change::React("🎉".to_string())).unwrap();
+        storage.change("bob", id, Change::Unreact("👍".to_string())).unwrap();
+        storage.change("bob", id, Change::Unreact("🎉".to_string())).unwrap();
+        storage.change("carol", id, Change::Unreact("🎉".to_string())).unwrap();
+        // Only the changes are written, not reacting twice or taking back what was not there
+        assert_eq!(file.lines(), 6);
+        drop(storage);
+
+        let storage = file.open();
+        assert_eq!(storage.reactions(id), vec![Reaction { emoji: "👍".to_string(), users: vec!["carol".to_string()] }]);
+    }
+
+    #[test]
+    fn rename_moves_messages_conversations_and_reactions() {
+        let file = TempFile::new("rename");
+        let mut storage = file.open();
+        let id = storage.append("alice", &direct("bob"), "hi bob", MessageKind::Text, 1, None).id;
+        storage.append("bob", &direct("alice"), "hi alice", MessageKind::Text, 1, None);
+        storage.change("bob", id, Change::React("👋".to_string())).unwrap();
+        storage.change("alice", id, Change::React("👋".to_string())).unwrap();
+        storage.rename("alice", "al");
+        drop(storage);
+
+        let mut storage = file.open();
+        assert_eq!(texts(&storage.history("al", &direct("bob"), None, 10, None)), vec![pair("al", "hi bob"), pair("bob", "hi alice")]);
+        assert_eq!(texts(&storage.history("bob", &direct("al"), None, 10, None)).len(), 2);
+        assert!(storage.history("bob", &direct("alice"), None, 10, None).is_empty());
+        assert_eq!(storage.reactions(id)[0].users, vec!["bob".to_string(), "al".to_string()]);
+        assert!(storage.change("al", id, Change::Edit("edited".to_string(), MessageKind::Text)).is_ok());
+        assert!(storage.change("alice", id, Change::Delete).is_err());
+    }
+
+    #[test]
+    fn cut_off_last_line_is_skipped() {
+        let file = TempFile::new("truncated");
+        let mut storage = file.open();
+        storage.append("alice", &direct("bob"), "one", MessageKind::Text, 1, None);
+        storage.append("alice", &direct("bob"), "two", MessageKind::Text, 2, None);
+        drop(storage);
+        let mut content = std::fs::OpenOptions::new().append(true).open(&file.0).unwrap();
+        content.write_all(br#"{"id":3,"conversation":{"Dir"#).unwrap();
+        drop(content);
+
+        let mut storage = file.open();
+        assert_eq!(storage.history("alice", &direct("bob"), None, 10, None).len(), 2);
+        assert_eq!(storage.append("alice", &direct("bob"), "three", MessageKind::Text, 3, None).id, 3);
+        drop(storage);
+
+        let storage = file.open();
+        assert_eq!(texts(&storage.history("bob", &direct("alice"), None, 10, None)), vec![
+            pair("alice", "one"), pair("alice", "two"), pair("alice", "three"),
+        ]);
+    }
+}
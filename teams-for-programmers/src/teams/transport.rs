@@ -0,0 +1,226 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+
+/// A connection the frames are sent over
+pub trait Transport: Read + Write + Send {
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<(), std::io::Error>;
+    /// Closes the connection in both directions, so the peer notices right away
+    fn shutdown(&mut self) -> Result<(), std::io::Error>;
+}
+
+impl Transport for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> Result<(), std::io::Error> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn shutdown(&mut self) -> Result<(), std::io::Error> {
+        TcpStream::shutdown(self, std::net::Shutdown::Both)
+    }
+}
+
+/// The sockets of the server event loop never block
+impl Transport for mio::net::TcpStream {
+    fn set_nonblocking(&self, _nonblocking: bool) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> Result<(), std::io::Error> {
+        mio::net::TcpStream::shutdown(self, std::net::Shutdown::Both)
+    }
+}
+
+/// A tls session over a socket that never blocks. `recv` and `send` already wait on WouldBlock
+/// and the server waits for the socket to be ready, so the connection can not be switched to blocking.
+struct TlsStream<S> {
+    connection: rustls::Connection,
+    socket: S,
+}
+
+fn would_block(e: &std::io::Error) -> bool {
+    e.kind() == std::io::ErrorKind::WouldBlock
+}
+
+fn tls_error(e: rustls::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+impl<S: Read + Write> TlsStream<S> {
+    /// Writes the encrypted records rustls has buffered to the socket
+    fn write_records(&mut self) -> Result<(), std::io::Error> {
+        while self.connection.wants_write() {
+            self.connection.write_tls(&mut self.socket)?;
+        }
+        Ok(())
+    }
+
+    /// Like `write_records`, but leaves what does not fit into the socket for the next call
+    fn try_write_records(&mut self) -> Result<(), std::io::Error> {
+        match self.write_records() {
+            Err(e) if !would_block(&e) => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<S: Read + Write> Read for TlsStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            // The handshake needs answers before any data arrives
+            self.try_write_records()?;
+            match self.connection.reader().read(buf) {
+                Err(e) if would_block(&e) => {},
+                result => return result,
+            }
+            if self.connection.read_tls(&mut self.socket)? == 0 {
+                return Ok(0);
+            }
+            if let Err(e) = self.connection.process_new_packets() {
+                // Tell the peer what went wrong before giving up
+                let _ = self.try_write_records();
+                return Err(tls_error(e));
+            }
+        }
+    }
+}
+
+impl<S: Read + Write> Write for TlsStream<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.try_write_records()?;
+        match self.connection.writer().write(buf)? {
+            // The buffer of rustls is full until the socket takes some of it
+            0 if !buf.is_empty() => Err(std::io::Error::from(std::io::ErrorKind::WouldBlock)),
+            n => {
+                self.try_write_records()?;
+                Ok(n)
+            },
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write_records()
+    }
+}
+
+impl<S: Transport> Transport for TlsStream<S> {
+    fn set_nonblocking(&self, _nonblocking: bool) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> Result<(), std::io::Error> {
+        self.connection.send_close_notify();
+        self.try_write_records()?;
+        self.socket.shutdown()
+    }
+}
+
+fn invalid_input(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+fn read_certificates(path: &std::path::Path) -> Result<Vec<CertificateDer<'static>>, std::io::Error> {
+    let certificates = CertificateDer::pem_file_iter(path)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid_input(format!("Could not read the certificates in {:?}: {}", path, e)))?;
+    match certificates.is_empty() {
+        true => Err(invalid_input(format!("There are no certificates in {:?}", path))),
+        false => Ok(certificates),
+    }
+}
+
+/// How the server wraps the connections it accepts
+pub enum Acceptor {
+    Plain,
+    Tls(Arc<rustls::ServerConfig>),
+}
+
+impl Acceptor {
+    /// Tls needs both the certificate chain and its private key, without either the connections stay plain tcp
+    pub fn new(certificate: Option<&std::path::Path>, key: Option<&std::path::Path>) -> Result<Acceptor, std::io::Error> {
+        let (certificate, key) = match (certificate, key) {
+            (Some(certificate), Some(key)) => (certificate, key),
+            (None, None) => {
+                log::warn!("No tls certificate configured, the connections are not encrypted");
+                return Ok(Acceptor::Plain);
+            },
+            _ => return Err(invalid_input("Tls needs both the certificate and the key".to_string())),
+        };
+
+        let certificates = read_certificates(certificate)?;
+        let key = PrivateKeyDer::from_pem_file(key)
+            .map_err(|e| invalid_input(format!("Could not read the private key in {:?}: {}", key, e)))?;
+        let config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(certificates, key)
+            .map_err(|e| invalid_input(format!("The certificate does not fit the key: {}", e)))?;
+        log::info!("Accept tls connections with the certificate {:?}", certificate);
+        Ok(Acceptor::Tls(Arc::new(config)))
+    }
+
+    /// The handshake happens with the first reads, so this does not wait for the client
+    pub fn accept(&self, stream: mio::net::TcpStream) -> Result<Box<dyn Transport>, std::io::Error> {
+        match self {
+            Acceptor::Plain => Ok(Box::new(stream)),
+            Acceptor::Tls(config) => {
+                let connection = rustls::ServerConnection::new(config.clone()).map_err(tls_error)?;
+                Ok(Box::new(TlsStream { connection: connection.into(), socket: stream }))
+            },
+        }
+    }
+}
+
+/// How the client connects to the server
+pub enum Connector {
+    Plain,
+    /// Only server certificates issued by the configured CA are trusted, not the ones of the system
+    Tls {
+        config: Arc<rustls::ClientConfig>,
+        server_name: Option<String>,
+    },
+}
+
+impl Connector {
+    /// Without a CA the connection is plain tcp. `server_name` overrides the host of the address
+    /// for checking the certificate, e.g. when connecting to an ip address.
+    pub fn new(ca: Option<&std::path::Path>, server_name: Option<String>) -> Result<Connector, std::io::Error> {
+        let ca = match ca {
+            Some(ca) => ca,
+            None => {
+                log::warn!("No CA configured, the connection is not encrypted");
+                return Ok(Connector::Plain);
+            },
+        };
+
+        let mut roots = rustls::RootCertStore::empty();
+        for certificate in read_certificates(ca)? {
+            roots.add(certificate).map_err(|e| invalid_input(format!("Invalid CA certificate in {:?}: {}", ca, e)))?;
+        }
+        let config = rustls::ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(Connector::Tls { config: Arc::new(config), server_name })
+    }
+
+    pub fn connect(&self, address: &str) -> Result<Box<dyn Transport>, std::io::Error> {
+        let stream = TcpStream::connect(address)?;
+        match self {
+            Connector::Plain => Ok(Box::new(stream)),
+            Connector::Tls { config, server_name } => {
+                let name = server_name.clone().unwrap_or_else(|| host(address).to_string());
+                let name = ServerName::try_from(name)
+                    .map_err(|e| invalid_input(format!("Invalid server name: {}", e)))?;
+                let connection = rustls::ClientConnection::new(config.clone(), name).map_err(tls_error)?;
+                stream.set_nonblocking(true)?;
+                Ok(Box::new(TlsStream { connection: connection.into(), socket: stream }))
+            },
+        }
+    }
+}
+
+/// The address without the port, "[::1]:7474" becomes "::1"
+fn host(address: &str) -> &str {
+    let host = address.rsplit_once(':').map(|(host, _)| host).unwrap_or(address);
+    host.trim_start_matches('[').trim_end_matches(']')
+}